@@ -1,6 +1,4 @@
 use anyhow::Result;
-use futures_util::StreamExt;
-use futures_util::{future, Future};
 use russh::*;
 use sshrpc::client::SshRpcExt;
 use sshrpc::{Error};
@@ -12,11 +10,6 @@ use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
 use tarpc::context;
-use tarpc::server::Channel;
-
-async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
-    tokio::spawn(fut);
-}
 
 // implement of tarpc
 #[tarpc::service]
@@ -51,23 +44,8 @@ impl client::Handler for Client {
 
 #[allow(dead_code)]
 async fn do_server() -> Result<(), anyhow::Error> {
-    let mut listener = sshrpc::transport::listen(1).await?;
-
-    listener.config_mut().max_frame_length(usize::MAX);
-
-    listener
-        // Ignore accept errors.
-        .filter_map(|r| future::ready(r.ok()))
-        .map(tarpc::server::BaseChannel::with_defaults)
-        .map(|channel| {
-            let server = HelloServer;
-            channel.execute(server.serve()).for_each(spawn)
-        })
-        // only one for single connection
-        .take(1)
-        .buffer_unordered(1)
-        .for_each(|_| async {})
-        .await;
+    let options = sshrpc::transport::ServeOptions::new().max_frame_length(usize::MAX);
+    sshrpc::transport::serve_with(1, HelloServer.serve(), &options).await?;
     Ok(())
 }
 
@@ -84,8 +62,11 @@ async fn do_client(server: &str, user: &str, pass: &str) -> Result<(), anyhow::E
 
     // Use this example serever
     let bin = tokio::fs::File::open("/proc/self/exe").await?;
-    let s = session.exec_rpc_server(bin, "").await?;
-    let (mut channel, transport) = s.try_into_transport(1)?;
+    let mut s = session.exec_rpc_server(bin, "").await?;
+    let exit = s
+        .server_exit()
+        .expect("channel of execution is available in port forward mode");
+    let (_, transport) = s.try_into_transport(1)?;
 
     let client = WorldClient::new(tarpc::client::Config::default(), transport).spawn();
 
@@ -110,35 +91,14 @@ async fn do_client(server: &str, user: &str, pass: &str) -> Result<(), anyhow::E
         );
     });
 
-    let mut code = None;
+    // waits until the server exits, stdout and stderr of server are captured meanwhile
+    let status = exit.wait().await;
     let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&exit.stdout())?;
+    stdout.write_all(&exit.stderr())?;
+    stdout.flush()?;
 
-    loop {
-        // There's an event available on the session channel
-        let Some(msg) = channel.wait().await else {
-            break;
-        };
-        match msg {
-            // stdout of server
-            ChannelMsg::Data { ref data } => {
-                stdout.write_all(data)?;
-                stdout.flush()?;
-            }
-            // stderr of server
-            ChannelMsg::ExtendedData { ref data, ext: 1 } => {
-                stdout.write_all(data)?;
-                stdout.flush()?;
-            }
-            // exit code of server
-            ChannelMsg::ExitStatus { exit_status } => {
-                code = Some(exit_status);
-                // cannot leave the loop immediately, there might still be more data to receive
-            }
-            _ => {}
-        }
-    }
-
-    println!("Exit code: {:?}", code);
+    println!("Exit: {:?}", status);
 
     session
         .disconnect(Disconnect::ByApplication, "", "English")
@@ -147,14 +107,21 @@ async fn do_client(server: &str, user: &str, pass: &str) -> Result<(), anyhow::E
     Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), anyhow::Error> {
+fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
 
     let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        // before the runtime starts any thread
+        sshrpc::transport::take_auth_token();
+        sshrpc::transport::take_unlink_self();
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
     if args.len() == 4 {
-        do_client(&args[1], &args[2], &args[3]).await
+        runtime.block_on(do_client(&args[1], &args[2], &args[3]))
     } else {
-        do_server().await
+        runtime.block_on(do_server())
     }
 }
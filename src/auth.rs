@@ -0,0 +1,60 @@
+//! Authentication token between the client and the forwarded server port.
+//!
+//! The client passes a random token to the server through stdin when it launches the server.
+//! Then the client sends the token as the first length delimited frame of each connection.
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const TOKEN_LEN: usize = 32;
+
+pub(crate) fn generate_token() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+    Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LEN)
+}
+
+/// Encode the token as the same format as `LengthDelimitedCodec::new()`.
+pub(crate) fn token_frame(token: &str) -> Vec<u8> {
+    let mut frame = (token.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(token.as_bytes());
+    frame
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Read the first frame and check that it is the token.
+/// This reads exactly the frame, so the rest of the stream is kept for the protocol.
+pub(crate) async fn verify_token<S>(stream: &mut S, token: &[u8]) -> Result<bool, std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u32().await? as usize;
+    if len != token.len() {
+        return Ok(false);
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(constant_time_eq(&buf, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), TOKEN_LEN);
+
+        let mut frame = token_frame(&token);
+        frame.extend_from_slice(b"rest");
+        let mut stream = &frame[..];
+        assert!(verify_token(&mut stream, token.as_bytes()).await.unwrap());
+        assert_eq!(stream, b"rest");
+
+        let frame = token_frame(&generate_token());
+        assert!(!verify_token(&mut &frame[..], token.as_bytes())
+            .await
+            .unwrap());
+    }
+}
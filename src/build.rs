@@ -0,0 +1,121 @@
+//! Helpers for `build.rs` to embed server binaries into the client executable.
+//!
+//! ```no_run
+//! // build.rs
+//! sshrpc::build::EmbedBinaries::new("server")
+//!     .binary("x86_64-unknown-linux-musl", "bin/server-x86_64")
+//!     .binary("aarch64-unknown-linux-musl", "bin/server-aarch64")
+//!     .write()
+//!     .unwrap();
+//! ```
+//!
+//! ```ignore
+//! // main.rs
+//! let binaries = sshrpc::include_binaries!("server");
+//! let session = handle
+//!     .exec_rpc_server_multiarch(binaries.iter(), &LaunchSpec::new())
+//!     .await?;
+//! ```
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file generated in `OUT_DIR` for the set of binaries `name`.
+pub fn file_name(name: &str) -> String {
+    format!("sshrpc_binaries_{}.rs", name)
+}
+
+/// Builder of the list of binaries for `include_binaries!`.
+#[derive(Debug, Clone)]
+pub struct EmbedBinaries {
+    name: String,
+    binaries: Vec<(String, PathBuf)>,
+}
+
+impl EmbedBinaries {
+    /// `name` distinguishes several sets of binaries in one crate.
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Self {
+            name: name.into(),
+            binaries: vec![],
+        }
+    }
+
+    /// Embed the binary at `path` for the target `triple`.
+    /// Relative paths are resolved from `CARGO_MANIFEST_DIR`.
+    pub fn binary<T: Into<String>, P: Into<PathBuf>>(mut self, triple: T, path: P) -> Self {
+        self.binaries.push((triple.into(), path.into()));
+        self
+    }
+
+    /// Embed `<target_dir>/<triple>/<profile>/<bin>`, as built by `cargo build --target <triple>`.
+    pub fn cargo_binary<T, D>(self, triple: T, target_dir: D, profile: &str, bin: &str) -> Self
+    where
+        T: Into<String>,
+        D: AsRef<Path>,
+    {
+        let triple = triple.into();
+        let path = target_dir.as_ref().join(&triple).join(profile).join(bin);
+        self.binary(triple, path)
+    }
+
+    /// Generate the source included by `include_binaries!`.
+    fn render(&self, base: &Path) -> io::Result<String> {
+        let mut src = "&[\n".to_string();
+        for (triple, path) in &self.binaries {
+            let path = base.join(path);
+            if !path.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("binary for {} not found: {}", triple, path.display()),
+                ));
+            }
+            src.push_str(&format!(
+                "    ({:?}, include_bytes!({:?}) as &[u8]),\n",
+                triple,
+                path.to_string_lossy()
+            ));
+        }
+        src.push(']');
+        Ok(src)
+    }
+
+    /// Write the list to `OUT_DIR` and tell cargo to rerun when a binary changes.
+    pub fn write(&self) -> io::Result<()> {
+        let out_dir = std::env::var_os("OUT_DIR")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set"))?;
+        let base = std::env::var_os("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let src = self.render(&base)?;
+        for (_, path) in &self.binaries {
+            println!("cargo:rerun-if-changed={}", base.join(path).display());
+        }
+        std::fs::write(Path::new(&out_dir).join(file_name(&self.name)), src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let src = EmbedBinaries::new("server")
+            .binary("x86_64-unknown-linux-gnu", "Cargo.toml")
+            .render(manifest_dir)
+            .unwrap();
+        let path = manifest_dir.join("Cargo.toml");
+        assert_eq!(
+            src,
+            format!(
+                "&[\n    (\"x86_64-unknown-linux-gnu\", include_bytes!({:?}) as &[u8]),\n]",
+                path.to_string_lossy()
+            )
+        );
+        assert!(EmbedBinaries::new("server")
+            .binary("x86_64-unknown-linux-gnu", "not-found")
+            .render(manifest_dir)
+            .is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Run commands on remote, e.g. for checks before and after launching the server.
+//!
+//! ```ignore
+//! use sshrpc::client::command::{Command, CommandExt};
+//!
+//! let output = handle.output("systemctl is-active nginx").await?.check()?;
+//! let mut child = handle.spawn(&Command::sh("tail -f /var/log/syslog")).await?;
+//! let mut lines = child.stdout.lines();
+//! while let Some(line) = lines.next().await {
+//!     println!("{}", line?);
+//! }
+//! ```
+use crate::client::launch::sh_c;
+use crate::client::ExitStatus;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+/// A command to run on remote.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub(crate) command: Vec<u8>,
+    pub(crate) stdin: Option<Vec<u8>>,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl Command {
+    /// `command` is a command line for the login shell of the remote user.
+    pub fn new<C: Into<Vec<u8>>>(command: C) -> Self {
+        Self {
+            command: command.into(),
+            stdin: None,
+            timeout: None,
+        }
+    }
+
+    /// Run `script` with `sh -c`, whatever the login shell is.
+    pub fn sh<S: AsRef<[u8]>>(script: S) -> Self {
+        Self::new(sh_c(script))
+    }
+
+    /// Data written to stdin first.
+    /// `CommandExt::run` sends EOF after this, `CommandExt::spawn` keeps stdin open.
+    pub fn stdin<D: Into<Vec<u8>>>(mut self, data: D) -> Self {
+        self.stdin = Some(data.into());
+        self
+    }
+
+    /// Give up waiting after `timeout` (default: no timeout).
+    /// The channel is closed then, but the remote process may keep running.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The command line, for implementations of `CommandExt`.
+    pub fn get_command(&self) -> &[u8] {
+        &self.command
+    }
+
+    /// Data written to stdin first, see `stdin`.
+    pub fn get_stdin(&self) -> Option<&[u8]> {
+        self.stdin.as_deref()
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// Captured output of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `None` when the channel is closed without exit status
+    pub status: Option<ExitStatus>,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status.as_ref().is_some_and(ExitStatus::success)
+    }
+
+    /// Turn an unsuccessful exit into `CommandFailed`.
+    pub fn check(self) -> Result<Self, CommandFailed> {
+        if self.success() {
+            return Ok(self);
+        }
+        Err(CommandFailed {
+            status: self.status,
+            stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+        })
+    }
+}
+
+/// A command exited unsuccessfully, see `Output::check`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "Command failed with {}: {}",
+    .status.as_ref().map_or("no exit status".to_string(), ToString::to_string),
+    .stderr.trim_end()
+)]
+pub struct CommandFailed {
+    pub status: Option<ExitStatus>,
+    pub stderr: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub enum CommandError {
+    RusshError(#[from] russh::Error),
+    IoError(#[from] std::io::Error),
+    #[error("Command timed out after {0:?}")]
+    Timeout(Duration),
+    Failed(#[from] CommandFailed),
+}
+
+/// stdin of a spawned command. Shut it down to send EOF.
+pub type ChildStdin = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// stdout or stderr of a spawned command.
+/// Data is buffered without limit until it is read, so reading one does not block the other.
+pub struct ChildOutput {
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChildOutput {
+    /// Output read from the chunks sent to `rx`. It ends when all senders are dropped.
+    pub fn new(rx: mpsc::UnboundedReceiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Stream of the lines, without the line endings.
+    pub fn lines(self) -> FramedRead<Self, LinesCodec> {
+        FramedRead::new(self, LinesCodec::new())
+    }
+}
+
+impl AsyncRead for ChildOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.pos == self.buf.len() {
+            match std::task::ready!(self.rx.poll_recv(cx)) {
+                Some(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = out.remaining().min(self.buf.len() - self.pos);
+        out.put_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A command running on remote, made by `CommandExt::spawn`.
+pub struct Child {
+    pub stdin: Option<ChildStdin>,
+    pub stdout: ChildOutput,
+    pub stderr: ChildOutput,
+    status: oneshot::Receiver<Option<ExitStatus>>,
+    close: mpsc::Sender<()>,
+    timeout: Option<(Instant, Duration)>,
+}
+
+impl Child {
+    /// `status` gets the exit status when the channel is closed, and `close` asks to close it.
+    /// This is for implementations of `CommandExt`, see `client::russh` for an example.
+    pub fn new(
+        stdin: ChildStdin,
+        stdout: ChildOutput,
+        stderr: ChildOutput,
+        status: oneshot::Receiver<Option<ExitStatus>>,
+        close: mpsc::Sender<()>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            stdin: Some(stdin),
+            stdout,
+            stderr,
+            status,
+            close,
+            timeout: timeout.map(|timeout| (Instant::now() + timeout, timeout)),
+        }
+    }
+
+    /// Count `timeout` from `start` instead of the call of `new`,
+    /// e.g. to include the time to open the channel and to write stdin.
+    pub fn started_at(mut self, start: Instant) -> Self {
+        if let Some((deadline, timeout)) = &mut self.timeout {
+            *deadline = start + *timeout;
+        }
+        self
+    }
+
+    /// Send EOF to stdin, and drop it.
+    async fn close_stdin(&mut self) -> Result<(), CommandError> {
+        if let Some(mut stdin) = self.stdin.take() {
+            stdin.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    /// Close stdin and wait until the command exits.
+    /// stdout and stderr can still be read after this.
+    pub async fn wait(&mut self) -> Result<Option<ExitStatus>, CommandError> {
+        self.close_stdin().await?;
+        let status = &mut self.status;
+        with_deadline(self.timeout, &self.close, async {
+            Ok(status.await.ok().flatten())
+        })
+        .await
+    }
+
+    /// Close stdin and capture the rest of stdout and stderr until the command exits.
+    pub async fn output(mut self) -> Result<Output, CommandError> {
+        self.close_stdin().await?;
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let (out, err, status) = (&mut self.stdout, &mut self.stderr, &mut self.status);
+        let status = with_deadline(self.timeout, &self.close, async {
+            let (out, err) =
+                tokio::join!(out.read_to_end(&mut stdout), err.read_to_end(&mut stderr));
+            out?;
+            err?;
+            Ok(status.await.ok().flatten())
+        })
+        .await?;
+        Ok(Output {
+            stdout,
+            stderr,
+            status,
+        })
+    }
+}
+
+/// Await `future` until the deadline of `timeout`, and ask to close the channel on timeout.
+async fn with_deadline<F, T>(
+    timeout: Option<(Instant, Duration)>,
+    close: &mpsc::Sender<()>,
+    future: F,
+) -> Result<T, CommandError>
+where
+    F: std::future::Future<Output = Result<T, CommandError>>,
+{
+    let Some((deadline, timeout)) = timeout else {
+        return future.await;
+    };
+    match tokio::time::timeout_at(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => {
+            let _ = close.try_send(());
+            Err(CommandError::Timeout(timeout))
+        }
+    }
+}
+
+/// Run commands on remote.
+/// Implementations make a `Child` with `Child::new` and `ChildOutput::new`.
+#[allow(async_fn_in_trait)]
+pub trait CommandExt {
+    type Error;
+
+    /// Run `command` and capture its output.
+    async fn run(&self, command: &Command) -> Result<Output, Self::Error>;
+
+    /// Start `command` with its stdin, stdout and stderr connected to the returned `Child`.
+    async fn spawn(&self, command: &Command) -> Result<Child, Self::Error>;
+
+    /// Run the command line `command` and capture its output.
+    async fn output<C: Into<Vec<u8>>>(&self, command: C) -> Result<Output, Self::Error> {
+        self.run(&Command::new(command)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let output = Output {
+            stdout: vec![],
+            stderr: b"no such file\n".to_vec(),
+            status: Some(ExitStatus::Code(2)),
+        };
+        assert_eq!(
+            output.check().unwrap_err().to_string(),
+            "Command failed with exit code 2: no such file"
+        );
+        let output = Output {
+            stdout: b"ok".to_vec(),
+            stderr: vec![],
+            status: Some(ExitStatus::Code(0)),
+        };
+        assert!(output.check().is_ok());
+    }
+}
@@ -0,0 +1,132 @@
+//! Compression of the binary while uploading.
+//!
+//! The remote side decompresses it with the command line tool (`zstd`, `xz` or `gzip`).
+//! Each algorithm is available when the feature of the same name is enabled.
+use std::pin::Pin;
+use std::task::{Context, Poll};
+#[cfg(any(feature = "zstd", feature = "xz", feature = "gzip"))]
+use tokio::io::BufReader;
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Compression {
+    Zstd,
+    Xz,
+    Gzip,
+}
+
+impl Compression {
+    /// All algorithms in the order of preference.
+    pub const ALL: [Compression; 3] = [Compression::Zstd, Compression::Xz, Compression::Gzip];
+
+    /// Whether this algorithm is enabled by the crate features.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Xz => cfg!(feature = "xz"),
+            Compression::Gzip => cfg!(feature = "gzip"),
+        }
+    }
+
+    /// Name of the command line tool on remote.
+    pub fn tool(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// Command to decompress stdin into stdout on remote.
+    pub fn decompress_command(&self) -> String {
+        format!("{} -dc", self.tool())
+    }
+}
+
+/// Shell script printing the available tools on remote, one per line.
+pub(crate) fn probe_command(preferred: &[Compression]) -> String {
+    let tools: Vec<&str> = preferred.iter().map(|c| c.tool()).collect();
+    format!(
+        "for c in {}; do command -v $c >/dev/null 2>&1 && echo $c; done; true",
+        tools.join(" ")
+    )
+}
+
+/// Choose the first algorithm supported on both sides.
+pub(crate) fn choose(preferred: &[Compression], remote_tools: &str) -> Option<Compression> {
+    preferred
+        .iter()
+        .copied()
+        .filter(Compression::is_supported)
+        .find(|c| remote_tools.lines().any(|tool| tool.trim() == c.tool()))
+}
+
+/// Compressing reader made by `encoder`.
+/// This is an enum rather than a boxed trait object, so it is `Send` when `R` is.
+pub(crate) enum Encoder<R> {
+    Raw(R),
+    #[cfg(feature = "zstd")]
+    Zstd(async_compression::tokio::bufread::ZstdEncoder<BufReader<R>>),
+    #[cfg(feature = "xz")]
+    Xz(async_compression::tokio::bufread::XzEncoder<BufReader<R>>),
+    #[cfg(feature = "gzip")]
+    Gzip(async_compression::tokio::bufread::GzipEncoder<BufReader<R>>),
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Encoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Encoder::Raw(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Compress `binary` with `compression`, or pass it through when `None`.
+pub(crate) fn encoder<R>(binary: R, compression: Option<Compression>) -> Encoder<R>
+where
+    R: AsyncRead + Unpin,
+{
+    match compression {
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => Encoder::Zstd(
+            async_compression::tokio::bufread::ZstdEncoder::new(BufReader::new(binary)),
+        ),
+        #[cfg(feature = "xz")]
+        Some(Compression::Xz) => Encoder::Xz(async_compression::tokio::bufread::XzEncoder::new(
+            BufReader::new(binary),
+        )),
+        #[cfg(feature = "gzip")]
+        Some(Compression::Gzip) => Encoder::Gzip(
+            async_compression::tokio::bufread::GzipEncoder::new(BufReader::new(binary)),
+        ),
+        _ => Encoder::Raw(binary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let remote = "xz\ngzip\n";
+        let expected = Compression::ALL
+            .into_iter()
+            .filter(|c| *c != Compression::Zstd)
+            .find(Compression::is_supported);
+        assert_eq!(choose(&Compression::ALL, remote), expected);
+        assert_eq!(choose(&[Compression::Zstd], remote), None);
+        assert_eq!(choose(&Compression::ALL, ""), None);
+    }
+}
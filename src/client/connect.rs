@@ -0,0 +1,426 @@
+//! Connect to a host and get a typed tarpc client in one call.
+//!
+//! ```ignore
+//! let (client, guard) = ConnectBuilder::new("example.com", "user")
+//!     .private_key("/home/user/.ssh/id_ed25519", None)
+//!     .binary(std::fs::read("target/x86_64-unknown-linux-musl/release/server")?)
+//!     .connect(|transport| WorldClient::new(Default::default(), transport).spawn())
+//!     .await?;
+//! client.hello(context::current(), "world".into()).await?;
+//! drop(client);
+//! let status = guard.wait().await;
+//! ```
+use crate::client::command::CommandError;
+use crate::client::russh::RpcStartError;
+use crate::client::{
+    AppProtocolError, EmbeddedBinaries, ExitStatus, KillError, LaunchMethod, LaunchSpec,
+    ServerExit, SshRpcExt,
+};
+use crate::remote_log::ServerLog;
+use crate::transport::BincodeTransport;
+use crate::AppProtocolVersions;
+use russh::client::{Handle, Msg};
+use russh::keys::key::{KeyPair, PublicKey};
+use russh::{ChannelStream, Disconnect, Sig};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub enum ConnectError {
+    #[error("Authentication failed for {0}")]
+    AuthenticationFailed(String),
+    #[error("No binary to launch")]
+    NoBinary,
+    KeyError(#[from] russh::keys::Error),
+    RpcStartError(#[from] RpcStartError),
+    AppProtocolError(#[from] AppProtocolError),
+    RusshError(#[from] russh::Error),
+}
+
+/// Handler checking the host key with `~/.ssh/known_hosts`.
+pub struct KnownHosts {
+    host: String,
+    port: u16,
+    accept_unknown: bool,
+}
+
+#[async_trait::async_trait]
+impl russh::client::Handler for KnownHosts {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+        if self.accept_unknown {
+            return Ok(true);
+        }
+        match russh::keys::check_known_hosts(&self.host, self.port, key) {
+            Ok(known) => Ok(known),
+            Err(e) => {
+                tracing::warn!("host key of {} is not accepted: {}", self.host, e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+enum Auth {
+    None,
+    Password(String),
+    PrivateKey(PathBuf, Option<String>),
+    KeyPair(Arc<KeyPair>),
+}
+
+enum Binary {
+    None,
+    Single(Arc<[u8]>),
+    Embedded(EmbeddedBinaries),
+}
+
+/// Builder to connect, authenticate, launch the server and make a client.
+pub struct ConnectBuilder {
+    host: String,
+    port: u16,
+    user: String,
+    auth: Auth,
+    accept_unknown_host_key: bool,
+    config: russh::client::Config,
+    binary: Binary,
+    spec: LaunchSpec,
+    app_protocol_versions: AppProtocolVersions,
+}
+
+impl ConnectBuilder {
+    pub fn new<H: Into<String>, U: Into<String>>(host: H, user: U) -> Self {
+        Self {
+            host: host.into(),
+            port: 22,
+            user: user.into(),
+            auth: Auth::None,
+            accept_unknown_host_key: false,
+            config: russh::client::Config::default(),
+            binary: Binary::None,
+            spec: LaunchSpec::new(),
+            app_protocol_versions: 1.into(),
+        }
+    }
+
+    /// SSH port (default: 22)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn password<P: Into<String>>(mut self, password: P) -> Self {
+        self.auth = Auth::Password(password.into());
+        self
+    }
+
+    /// Private key file like `~/.ssh/id_ed25519`, with its passphrase if encrypted.
+    pub fn private_key<P: Into<PathBuf>>(mut self, path: P, passphrase: Option<&str>) -> Self {
+        self.auth = Auth::PrivateKey(path.into(), passphrase.map(str::to_string));
+        self
+    }
+
+    pub fn key_pair(mut self, key: Arc<KeyPair>) -> Self {
+        self.auth = Auth::KeyPair(key);
+        self
+    }
+
+    /// Accept host keys not in `~/.ssh/known_hosts` (default: false).
+    pub fn accept_unknown_host_key(mut self, accept: bool) -> Self {
+        self.accept_unknown_host_key = accept;
+        self
+    }
+
+    /// Configuration of the SSH client, e.g. `inactivity_timeout`.
+    pub fn config(mut self, config: russh::client::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The server binary.
+    pub fn binary<B: Into<Arc<[u8]>>>(mut self, binary: B) -> Self {
+        self.binary = Binary::Single(binary.into());
+        self
+    }
+
+    /// Server binaries for several targets, the one for the remote host is chosen.
+    pub fn embedded_binaries(mut self, binaries: EmbeddedBinaries) -> Self {
+        self.binary = Binary::Embedded(binaries);
+        self
+    }
+
+    pub fn launch_spec(mut self, spec: LaunchSpec) -> Self {
+        self.spec = spec;
+        self
+    }
+
+    /// App protocol versions advertised to the server (see `LaunchSpec::app_protocol_versions`)
+    /// and given to `SshRpcSession::try_into_transport` (default: 1).
+    pub fn app_protocol_versions<V: Into<AppProtocolVersions>>(mut self, versions: V) -> Self {
+        self.app_protocol_versions = versions.into();
+        self
+    }
+
+    /// Connect and make a client with `make_client`,
+    /// like `|transport| WorldClient::new(Default::default(), transport).spawn()`.
+    pub async fn connect<M, T, Item, SinkItem>(
+        self,
+        make_client: M,
+    ) -> Result<(T, SessionGuard), ConnectError>
+    where
+        M: FnOnce(BincodeTransport<ChannelStream<Msg>, Item, SinkItem>) -> T,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        if matches!(self.binary, Binary::None) {
+            return Err(ConnectError::NoBinary);
+        }
+        let handler = KnownHosts {
+            host: self.host.clone(),
+            port: self.port,
+            accept_unknown: self.accept_unknown_host_key,
+        };
+        let mut handle = russh::client::connect(
+            Arc::new(self.config),
+            (self.host.as_str(), self.port),
+            handler,
+        )
+        .await?;
+
+        let authenticated = match self.auth {
+            Auth::None => handle.authenticate_none(&self.user).await?,
+            Auth::Password(password) => handle.authenticate_password(&self.user, password).await?,
+            Auth::PrivateKey(path, passphrase) => {
+                let key = russh::keys::load_secret_key(path, passphrase.as_deref())?;
+                handle
+                    .authenticate_publickey(&self.user, Arc::new(key))
+                    .await?
+            }
+            Auth::KeyPair(key) => handle.authenticate_publickey(&self.user, key).await?,
+        };
+        if !authenticated {
+            return Err(ConnectError::AuthenticationFailed(format!(
+                "{}@{}",
+                self.user, self.host
+            )));
+        }
+
+        let spec = self
+            .spec
+            .app_protocol_versions(self.app_protocol_versions.clone());
+        let session = match self.binary {
+            Binary::None => return Err(ConnectError::NoBinary),
+            Binary::Single(binary) => handle.exec_rpc_server_with(&binary[..], &spec).await?,
+            Binary::Embedded(binaries) => {
+                handle
+                    .exec_rpc_server_multiarch(binaries.iter(), &spec)
+                    .await?
+            }
+        };
+        let pid = session.handshake_information.pid;
+        let launch_method = session.launch_method.clone();
+        let (channel, transport) = session
+            .try_into_transport(self.app_protocol_versions)
+            .map_err(|e| e.error)?;
+        let client = make_client(transport);
+
+        let exit = channel.map(|channel| {
+            ServerExit::with_log(channel, pid, ServerLog::new(&self.host))
+                .launch_method(launch_method.as_ref())
+        });
+        Ok((
+            client,
+            SessionGuard {
+                handle,
+                exit,
+                launch_method,
+            },
+        ))
+    }
+}
+
+/// Keeps the SSH connection of a client made by `ConnectBuilder`.
+/// Output of the server goes to tracing, see `remote_log`. Dropping this closes the connection.
+pub struct SessionGuard {
+    handle: Handle<KnownHosts>,
+    exit: Option<ServerExit>,
+    /// to remove the staged binary in stdio mode, where there is no `exit`
+    launch_method: Option<LaunchMethod>,
+}
+
+impl SessionGuard {
+    pub fn handle(&self) -> &Handle<KnownHosts> {
+        &self.handle
+    }
+
+    /// Exit and output of the server. This is `None` in stdio mode.
+    pub fn server_exit(&self) -> Option<&ServerExit> {
+        self.exit.as_ref()
+    }
+
+    /// Exit status of the server, if it has exited.
+    /// This is always `None` in stdio mode.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit.as_ref()?.status()
+    }
+
+    /// Wait until the server exits and return its exit status.
+    /// Returns `None` if the channel is closed without exit status, or in stdio mode.
+    pub async fn wait(self) -> Option<ExitStatus> {
+        self.exit.as_ref()?.wait().await
+    }
+
+    /// Terminate the server with `sig`, see `ServerExit::kill`.
+    /// In stdio mode, the connection is closed instead.
+    pub async fn kill(&self, sig: Sig) -> Result<(), KillError> {
+        match &self.exit {
+            Some(exit) => exit.kill(&self.handle, sig).await,
+            None => Ok(self
+                .handle
+                .disconnect(Disconnect::ByApplication, "", "")
+                .await?),
+        }
+    }
+
+    /// Remove the staged binary of the server and close the connection.
+    /// The server exits when the client is dropped, see `transport::serve`.
+    pub async fn close(self) -> Result<(), CommandError> {
+        match (&self.exit, &self.launch_method) {
+            (Some(exit), _) => exit.remove_staged_file(&self.handle).await?,
+            (None, Some(launch_method)) => launch_method.remove_staged_file(&self.handle).await?,
+            (None, None) => (),
+        }
+        self.handle
+            .disconnect(Disconnect::ByApplication, "", "")
+            .await?;
+        Ok(())
+    }
+
+    /// Stop the server gracefully, then close the connection.
+    /// `client` is dropped first, see `ServerExit::shutdown`.
+    pub async fn shutdown<T>(self, client: T, timeout: Duration) -> Option<ExitStatus> {
+        let status = match &self.exit {
+            Some(exit) => exit.shutdown(&self.handle, client, timeout).await,
+            None => {
+                drop(client);
+                if let Some(launch_method) = &self.launch_method {
+                    if let Err(e) = launch_method.remove_staged_file(&self.handle).await {
+                        tracing::warn!("failed to remove the staged file: {}", e);
+                    }
+                }
+                None
+            }
+        };
+        let _ = self
+            .handle
+            .disconnect(Disconnect::ByApplication, "", "")
+            .await;
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_server::{self, PASSWORD};
+    use russh::ChannelMsg;
+    use std::net::SocketAddr;
+
+    type Transport = BincodeTransport<ChannelStream<Msg>, u32, u32>;
+
+    fn builder(addr: SocketAddr) -> ConnectBuilder {
+        ConnectBuilder::new(addr.ip().to_string(), "user")
+            .port(addr.port())
+            .binary(vec![0])
+    }
+
+    /// Guard of the command `command` run on a new test server.
+    async fn guard(command: &str, pid: bool) -> SessionGuard {
+        let addr = test_server::start().await;
+        let handler = KnownHosts {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            accept_unknown: true,
+        };
+        let mut handle = russh::client::connect(Default::default(), addr, handler)
+            .await
+            .unwrap();
+        assert!(handle
+            .authenticate_password("user", PASSWORD)
+            .await
+            .unwrap());
+        let mut channel = handle.channel_open_session().await.unwrap();
+        channel.exec(true, command).await.unwrap();
+        // the command prints its pid first, like the handshake information
+        let pid = if pid {
+            loop {
+                if let Some(ChannelMsg::Data { data }) = channel.wait().await {
+                    break Some(String::from_utf8_lossy(&data).trim().parse().unwrap());
+                }
+            }
+        } else {
+            None
+        };
+        SessionGuard {
+            handle,
+            exit: Some(ServerExit::new(channel, pid)),
+            launch_method: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors() {
+        let result = ConnectBuilder::new("localhost", "user")
+            .connect(|transport: Transport| transport)
+            .await;
+        assert!(matches!(result, Err(ConnectError::NoBinary)));
+
+        let addr = test_server::start().await;
+        let result = builder(addr)
+            .accept_unknown_host_key(true)
+            .password("wrong")
+            .connect(|transport: Transport| transport)
+            .await;
+        assert!(matches!(result, Err(ConnectError::AuthenticationFailed(_))));
+
+        // the key of the test server is not in known_hosts
+        let result = builder(addr)
+            .password(PASSWORD)
+            .connect(|transport: Transport| transport)
+            .await;
+        assert!(matches!(result, Err(ConnectError::RusshError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_session_guard_shutdown() {
+        let guard = guard("cat > /dev/null", false).await;
+        let status = guard.shutdown((), Duration::from_secs(5)).await;
+        assert_eq!(status, Some(ExitStatus::Code(0)));
+    }
+
+    #[tokio::test]
+    async fn test_session_guard_kill() {
+        let guard = guard("echo $$; exec sleep 30", true).await;
+        assert_eq!(guard.exit_status(), None);
+        // the test server ignores the signal request, so `kill` is run
+        guard.kill(Sig::TERM).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), guard.wait())
+            .await
+            .expect("the server is killed");
+    }
+
+    #[tokio::test]
+    async fn test_session_guard_close_stdio() {
+        let mut guard = guard("true", false).await;
+        let path = std::env::temp_dir().join(format!(".sshrpc.guard{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        // no `exit` in stdio mode
+        guard.exit = None;
+        guard.launch_method = Some(LaunchMethod::TmpFile(path.display().to_string()));
+        guard.close().await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,49 @@
+//! Server binaries embedded by `build::EmbedBinaries`.
+use crate::client::RemoteTarget;
+
+/// Server binaries for several target triples, created by `include_binaries!`.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedBinaries {
+    binaries: &'static [(&'static str, &'static [u8])],
+}
+
+impl EmbeddedBinaries {
+    pub const fn new(binaries: &'static [(&'static str, &'static [u8])]) -> Self {
+        Self { binaries }
+    }
+
+    /// Pairs of target triple and binary, to pass to `SshRpcExt::exec_rpc_server_multiarch`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &'static [u8])> {
+        self.binaries.iter().copied()
+    }
+
+    /// Target triples of the binaries.
+    pub fn triples(&self) -> impl Iterator<Item = &'static str> {
+        self.binaries.iter().map(|(triple, _)| *triple)
+    }
+
+    /// Binary for `triple`.
+    pub fn get(&self, triple: &str) -> Option<&'static [u8]> {
+        self.iter().find(|(t, _)| *t == triple).map(|(_, b)| b)
+    }
+
+    /// Binary which fits `target` best, to pass to `SshRpcExt::exec_rpc_server`.
+    pub fn select(&self, target: &RemoteTarget) -> Option<&'static [u8]> {
+        target
+            .select(self.triples())
+            .and_then(|triple| self.get(triple))
+    }
+}
+
+/// Include the binaries written by `build::EmbedBinaries::new(name).write()` in `build.rs`.
+#[macro_export]
+macro_rules! include_binaries {
+    ($name:literal) => {
+        $crate::client::embed::EmbeddedBinaries::new(include!(concat!(
+            env!("OUT_DIR"),
+            "/sshrpc_binaries_",
+            $name,
+            ".rs"
+        )))
+    };
+}
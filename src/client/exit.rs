@@ -0,0 +1,451 @@
+//! Exit of the launched server.
+use crate::client::command::{CommandError, CommandExt};
+use crate::client::launch::shell_quote;
+use crate::client::russh::remove_tmpfile;
+use crate::client::{LaunchMethod, SshRpcSession};
+use crate::remote_log::ServerLog;
+use russh::client::{Handle, Handler, Msg};
+use russh::{Channel, ChannelMsg, Sig};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, warn};
+
+/// Bytes of stdout and stderr kept by `ServerExit`, the older ones are dropped.
+pub const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// How long `ServerExit::kill` waits for the SSH `signal` request to work, before `kill` is run.
+pub const KILL_FALLBACK_DELAY: Duration = Duration::from_millis(500);
+
+/// Signals sent by `ServerExit::shutdown` in order, when the server does not exit by itself.
+const SHUTDOWN_SIGNALS: [Sig; 2] = [Sig::TERM, Sig::KILL];
+
+/// How the server exited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    Code(u32),
+    /// killed by `signal` (e.g. `KILL`, `SEGV`)
+    Signal {
+        signal: String,
+        core_dumped: bool,
+        error_message: String,
+    },
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, ExitStatus::Code(0))
+    }
+
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            ExitStatus::Code(code) => Some(*code),
+            ExitStatus::Signal { .. } => None,
+        }
+    }
+
+    pub(crate) fn from_msg(msg: &ChannelMsg) -> Option<Self> {
+        match msg {
+            ChannelMsg::ExitStatus { exit_status } => Some(ExitStatus::Code(*exit_status)),
+            ChannelMsg::ExitSignal {
+                signal_name,
+                core_dumped,
+                error_message,
+                ..
+            } => Some(ExitStatus::Signal {
+                signal: signal_name_of(signal_name),
+                core_dumped: *core_dumped,
+                error_message: error_message.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExitStatus::Code(code) => write!(f, "exit code {}", code),
+            ExitStatus::Signal {
+                signal,
+                core_dumped,
+                error_message,
+            } => {
+                write!(f, "killed by SIG{}", signal)?;
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                if !error_message.is_empty() {
+                    write!(f, ": {}", error_message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Name of `sig` without the `SIG` prefix.
+pub(crate) fn signal_name_of(sig: &Sig) -> String {
+    match sig {
+        Sig::Custom(name) => name.clone(),
+        sig => format!("{:?}", sig),
+    }
+}
+
+/// Command sending `sig` to `pid`, the fallback of the SSH `signal` request.
+fn kill_command(sig: &Sig, pid: u32) -> Vec<u8> {
+    let mut command = b"kill -s ".to_vec();
+    command.extend(shell_quote(signal_name_of(sig).as_bytes()));
+    command.extend(format!(" {}", pid).into_bytes());
+    command
+}
+
+#[derive(Default)]
+struct Captured {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+fn push_limited(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    if buf.len() > OUTPUT_LIMIT {
+        buf.drain(..buf.len() - OUTPUT_LIMIT);
+    }
+}
+
+/// Requests to the channel of execution, which is owned by the task of `ServerExit`.
+enum Command {
+    Signal(Sig, oneshot::Sender<Result<(), russh::Error>>),
+    Eof(oneshot::Sender<Result<(), russh::Error>>),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub enum KillError {
+    RusshError(#[from] russh::Error),
+    CommandError(#[from] CommandError),
+    #[error("The server ignored the signal and its pid is unknown")]
+    NoPid,
+    #[error("Failed to kill the server: {0}")]
+    KillCommandFail(String),
+}
+
+/// Watches the channel of execution until the server exits.
+/// stdout and stderr are captured (the last `OUTPUT_LIMIT` bytes each) and also go to tracing,
+/// see `remote_log`. Dropping this stops watching, but does not stop the server.
+pub struct ServerExit {
+    status: watch::Receiver<Option<ExitStatus>>,
+    captured: Arc<Mutex<Captured>>,
+    commands: mpsc::Sender<Command>,
+    pid: Option<u32>,
+    /// absolute path of the binary written to a tmp file, not quoted
+    staged_file: Mutex<Option<String>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ServerExit {
+    /// `pid` is the one in the handshake information, see `kill`.
+    pub fn new(channel: Channel<Msg>, pid: Option<u32>) -> Self {
+        Self::with_log(channel, pid, ServerLog::new(""))
+    }
+
+    pub(crate) fn with_log(
+        mut channel: Channel<Msg>,
+        pid: Option<u32>,
+        mut log: ServerLog,
+    ) -> Self {
+        let (tx, status) = watch::channel(None);
+        let (commands, mut command_rx) = mpsc::channel(1);
+        let captured = Arc::new(Mutex::new(Captured::default()));
+        let task = tokio::spawn({
+            let captured = captured.clone();
+            async move {
+                loop {
+                    let msg = tokio::select! {
+                        msg = channel.wait() => msg,
+                        Some(command) = command_rx.recv() => {
+                            match command {
+                                Command::Signal(sig, reply) => {
+                                    let _ = reply.send(channel.signal(sig).await);
+                                }
+                                Command::Eof(reply) => {
+                                    let _ = reply.send(channel.eof().await);
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    log.channel_msg(&msg);
+                    match msg {
+                        ChannelMsg::Data { ref data } => {
+                            push_limited(&mut captured.lock().unwrap().stdout, data)
+                        }
+                        ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                            push_limited(&mut captured.lock().unwrap().stderr, data)
+                        }
+                        ref msg => {
+                            if let Some(exit) = ExitStatus::from_msg(msg) {
+                                tx.send_replace(Some(exit));
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            status,
+            captured,
+            commands,
+            pid,
+            staged_file: Mutex::new(None),
+            task,
+        }
+    }
+
+    /// Remember the tmp file of `LaunchMethod::TmpFile` for `remove_staged_file`.
+    pub(crate) fn launch_method(self, launch_method: Option<&LaunchMethod>) -> Self {
+        if let Some(LaunchMethod::TmpFile(path)) = launch_method {
+            *self.staged_file.lock().unwrap() = Some(path.clone());
+        }
+        self
+    }
+
+    /// pid of the server, if the server reported it.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Exit status of the server, if it has exited.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status.borrow().clone()
+    }
+
+    /// Wait until the channel is closed and return the exit status.
+    /// Returns `None` if the channel is closed without exit status (e.g. the connection is lost).
+    pub async fn wait(&self) -> Option<ExitStatus> {
+        let mut status = self.status.clone();
+        while status.changed().await.is_ok() {}
+        let status = status.borrow().clone();
+        status
+    }
+
+    /// Wait until the server exits or the channel is closed, at most `timeout`.
+    /// Returns false on timeout.
+    async fn wait_exit_timeout(&self, timeout: Duration) -> bool {
+        let mut status = self.status.clone();
+        let exited = tokio::time::timeout(timeout, status.wait_for(Option::is_some)).await;
+        exited.is_ok()
+    }
+
+    /// stdout of the server after the handshake information.
+    pub fn stdout(&self) -> Vec<u8> {
+        self.captured.lock().unwrap().stdout.clone()
+    }
+
+    pub fn stderr(&self) -> Vec<u8> {
+        self.captured.lock().unwrap().stderr.clone()
+    }
+
+    async fn command<F>(&self, command: F) -> Result<(), russh::Error>
+    where
+        F: FnOnce(oneshot::Sender<Result<(), russh::Error>>) -> Command,
+    {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| russh::Error::SendError)?;
+        rx.await.map_err(|_| russh::Error::SendError)?
+    }
+
+    /// Send `sig` with the SSH `signal` request.
+    /// OpenSSH ignores it on many versions, use `kill` to make sure the server is stopped.
+    pub async fn signal(&self, sig: Sig) -> Result<(), russh::Error> {
+        self.command(|reply| Command::Signal(sig, reply)).await
+    }
+
+    /// Send EOF to stdin of the server.
+    pub async fn eof(&self) -> Result<(), russh::Error> {
+        self.command(Command::Eof).await
+    }
+
+    /// Terminate the server with `sig`.
+    /// The SSH `signal` request is tried first, and if the server is still alive after
+    /// `KILL_FALLBACK_DELAY`, `kill` is executed on `handle` against the pid of the server.
+    /// The fallback runs as the SSH user, so it cannot kill a server launched with `Privilege`.
+    pub async fn kill<H: Handler>(&self, handle: &Handle<H>, sig: Sig) -> Result<(), KillError> {
+        if self.status().is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.signal(sig.clone()).await {
+            debug!("signal request failed: {}", e);
+        }
+        if self.wait_exit_timeout(KILL_FALLBACK_DELAY).await {
+            return Ok(());
+        }
+        let pid = self.pid.ok_or(KillError::NoPid)?;
+        debug!(
+            "signal request is ignored, kill {} with SIG{}",
+            pid,
+            signal_name_of(&sig)
+        );
+        let output = handle.output(kill_command(&sig, pid)).await?;
+        if !output.success() && self.status().is_none() {
+            return Err(KillError::KillCommandFail(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Stop the server gracefully.
+    /// `transport` (or the client made of it) is dropped and EOF is sent first,
+    /// then the server is given `timeout` to exit, before it is killed with SIGTERM and SIGKILL.
+    pub async fn shutdown<H: Handler, T>(
+        &self,
+        handle: &Handle<H>,
+        transport: T,
+        timeout: Duration,
+    ) -> Option<ExitStatus> {
+        drop(transport);
+        let _ = self.eof().await;
+        for sig in SHUTDOWN_SIGNALS {
+            if self.wait_exit_timeout(timeout).await {
+                break;
+            }
+            warn!(
+                "server did not exit in {:?}, send SIG{}",
+                timeout,
+                signal_name_of(&sig)
+            );
+            if let Err(e) = self.kill(handle, sig).await {
+                warn!("failed to kill the server: {}", e);
+            }
+        }
+        self.wait_exit_timeout(timeout).await;
+        if let Err(e) = self.remove_staged_file(handle).await {
+            warn!("failed to remove the staged file: {}", e);
+        }
+        self.status()
+    }
+
+    /// Remove the binary written to a tmp file (`LaunchMethod::TmpFile`), if it is still there.
+    /// The server removes it after start, but it cannot when it runs as another user.
+    /// Files not removed are swept on the next launch.
+    pub async fn remove_staged_file<H: Handler>(
+        &self,
+        handle: &Handle<H>,
+    ) -> Result<(), CommandError> {
+        let Some(path) = self.staged_file.lock().unwrap().take() else {
+            return Ok(());
+        };
+        remove_tmpfile(handle, &path).await
+    }
+}
+
+impl LaunchMethod {
+    /// Remove the binary of `LaunchMethod::TmpFile`, if it is still there. Others have nothing
+    /// to remove. Keep a clone of `SshRpcSession::launch_method` to call this after
+    /// `SshRpcSession::try_into_transport`.
+    pub async fn remove_staged_file<H: Handler>(
+        &self,
+        handle: &Handle<H>,
+    ) -> Result<(), CommandError> {
+        match self {
+            LaunchMethod::TmpFile(path) => remove_tmpfile(handle, path).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for ServerExit {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<S> SshRpcSession<Channel<Msg>, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Take the channel of execution and watch it for the exit of the server.
+    /// Returns `None` in stdio mode, where the channel is the stream.
+    pub fn server_exit(&mut self) -> Option<ServerExit> {
+        let pid = self.handshake_information.pid;
+        let launch_method = self.launch_method.as_ref();
+        self.channel
+            .take()
+            .map(|channel| ServerExit::new(channel, pid).launch_method(launch_method))
+    }
+
+    /// Give up the session: close the channel of execution and remove the staged binary.
+    /// The SSH connection is kept.
+    pub async fn close<H: Handler>(self, handle: &Handle<H>) -> Result<(), CommandError> {
+        if let Some(channel) = self.channel {
+            let _ = channel.eof().await;
+            let _ = channel.close().await;
+        }
+        drop(self.stream);
+        match &self.launch_method {
+            Some(launch_method) => launch_method.remove_staged_file(handle).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_server;
+
+    #[test]
+    fn test_exit_status() {
+        let msg = ChannelMsg::ExitSignal {
+            signal_name: Sig::SEGV,
+            core_dumped: true,
+            error_message: String::new(),
+            lang_tag: String::new(),
+        };
+        let status = ExitStatus::from_msg(&msg).unwrap();
+        assert_eq!(status.to_string(), "killed by SIGSEGV (core dumped)");
+        assert!(!status.success());
+        let status = ExitStatus::from_msg(&ChannelMsg::ExitStatus { exit_status: 0 }).unwrap();
+        assert!(status.success());
+        assert_eq!(ExitStatus::from_msg(&ChannelMsg::Eof), None);
+    }
+
+    #[test]
+    fn test_kill_command() {
+        assert_eq!(kill_command(&Sig::TERM, 42), b"kill -s 'TERM' 42".to_vec());
+        assert_eq!(
+            kill_command(&Sig::Custom("USR1".to_string()), 42),
+            b"kill -s 'USR1' 42".to_vec()
+        );
+        let order: Vec<String> = SHUTDOWN_SIGNALS.iter().map(signal_name_of).collect();
+        assert_eq!(order, ["TERM", "KILL"]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_escalation() {
+        let handle = test_server::connect().await;
+        let mut channel = handle.channel_open_session().await.unwrap();
+        // ignores EOF and SIGTERM, so only SIGKILL stops it
+        channel
+            .exec(true, "trap '' TERM; echo $$; exec sleep 30")
+            .await
+            .unwrap();
+        let pid: u32 = loop {
+            if let Some(ChannelMsg::Data { data }) = channel.wait().await {
+                break String::from_utf8_lossy(&data).trim().parse().unwrap();
+            }
+        };
+        let exit = ServerExit::new(channel, Some(pid));
+        exit.shutdown(&handle, (), Duration::from_millis(200)).await;
+        let alive = handle.output(format!("kill -0 {}", pid)).await.unwrap();
+        assert!(!alive.success());
+    }
+}
@@ -0,0 +1,130 @@
+//! Launch HashiCorp go-plugin plugins.
+//!
+//! go-plugin plugins check the magic cookie environment variable and print a handshake
+//! with an optional server certificate as the sixth field.
+//! Plugins speaking `grpc` can be called with `SshRpcSession::try_into_grpc_channel`.
+//! With AutoMTLS (feature `auto-mtls`), give a `ClientCertificate` to `GoPluginConfig::auto_mtls`
+//! and call `SshRpcSession::try_into_grpc_channel_mtls` with the same certificate.
+//! `netrpc` plugins are not supported because it needs Go's `gob` encoding.
+use crate::client::LaunchSpec;
+
+#[cfg(feature = "auto-mtls")]
+pub(crate) mod mtls;
+#[cfg(feature = "auto-mtls")]
+pub use mtls::{ClientCertificate, MtlsError};
+
+/// go-plugin protocol versions the client supports
+pub const PLUGIN_PROTOCOL_VERSIONS: &str = "PLUGIN_PROTOCOL_VERSIONS";
+/// PEM encoded client certificate for AutoMTLS
+pub const PLUGIN_CLIENT_CERT: &str = "PLUGIN_CLIENT_CERT";
+
+/// Configuration of go-plugin handshake.
+/// This is the counterpart of `plugin.HandshakeConfig` and `AutoMTLS` of go-plugin.
+#[derive(Debug, Clone)]
+pub struct GoPluginConfig {
+    magic_cookie_key: String,
+    magic_cookie_value: String,
+    protocol_versions: Vec<u32>,
+    client_cert: Option<String>,
+}
+
+impl GoPluginConfig {
+    pub fn new<K: Into<String>, V: Into<String>>(
+        magic_cookie_key: K,
+        magic_cookie_value: V,
+    ) -> Self {
+        Self {
+            magic_cookie_key: magic_cookie_key.into(),
+            magic_cookie_value: magic_cookie_value.into(),
+            protocol_versions: vec![],
+            client_cert: None,
+        }
+    }
+
+    /// App protocol versions the client supports.
+    pub fn protocol_versions<I: IntoIterator<Item = u32>>(mut self, versions: I) -> Self {
+        self.protocol_versions = versions.into_iter().collect();
+        self
+    }
+
+    /// PEM encoded client certificate for AutoMTLS.
+    /// The plugin answers with its certificate in `HandshakeInformation::server_cert`,
+    /// so use both of them to make the tls connection over `SshRpcSession::stream`.
+    /// `SshRpcSession::try_into_grpc_channel` does not speak tls and rejects such a plugin.
+    pub fn client_cert<P: Into<String>>(mut self, pem: P) -> Self {
+        self.client_cert = Some(pem.into());
+        self
+    }
+
+    /// Enable AutoMTLS with `cert`.
+    /// Connect with `SshRpcSession::try_into_grpc_channel_mtls` and the same `cert`.
+    #[cfg(feature = "auto-mtls")]
+    pub fn auto_mtls(self, cert: &ClientCertificate) -> Self {
+        self.client_cert(cert.pem())
+    }
+
+    /// Environment variables to launch the plugin.
+    pub fn envs(&self) -> Vec<(String, String)> {
+        let mut envs = vec![(
+            self.magic_cookie_key.clone(),
+            self.magic_cookie_value.clone(),
+        )];
+        if !self.protocol_versions.is_empty() {
+            let versions = self
+                .protocol_versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            envs.push((PLUGIN_PROTOCOL_VERSIONS.to_string(), versions));
+        }
+        if let Some(cert) = &self.client_cert {
+            envs.push((PLUGIN_CLIENT_CERT.to_string(), cert.clone()));
+        }
+        envs
+    }
+
+    /// Add environment variables to `spec`.
+    /// The auth token is disabled, because plugins do not know it.
+    pub fn apply(&self, spec: LaunchSpec) -> LaunchSpec {
+        spec.envs(self.envs()).auth(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envs() {
+        let config = GoPluginConfig::new("BASIC_PLUGIN", "hello");
+        assert_eq!(
+            config.envs(),
+            vec![("BASIC_PLUGIN".to_string(), "hello".to_string())]
+        );
+
+        let config = config.protocol_versions([1, 3]).client_cert("PEM");
+        assert_eq!(
+            config.envs(),
+            vec![
+                ("BASIC_PLUGIN".to_string(), "hello".to_string()),
+                (PLUGIN_PROTOCOL_VERSIONS.to_string(), "1,3".to_string()),
+                (PLUGIN_CLIENT_CERT.to_string(), "PEM".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let spec = LaunchSpec::new().envs([("FOO", "bar")]);
+        let spec = GoPluginConfig::new("BASIC_PLUGIN", "hello").apply(spec);
+        assert!(!spec.auth);
+        assert_eq!(
+            spec.envs,
+            vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("BASIC_PLUGIN".to_string(), "hello".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! go-plugin AutoMTLS.
+//!
+//! The client generates a self-signed certificate and gives it to the plugin in `PLUGIN_CLIENT_CERT`.
+//! The plugin answers with its own self-signed certificate in the handshake,
+//! and both sides accept only the certificate of the other.
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Host name go-plugin puts in the certificates
+const SERVER_NAME: &str = "localhost";
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub enum MtlsError {
+    CertificateError(#[from] rcgen::Error),
+    InvalidServerCert(#[from] base64::DecodeError),
+    TlsError(#[from] rustls::Error),
+    IoError(#[from] std::io::Error),
+}
+
+/// Client certificate for AutoMTLS.
+/// Give it to `GoPluginConfig::auto_mtls` and `SshRpcSession::try_into_grpc_channel_mtls`.
+pub struct ClientCertificate {
+    pem: String,
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl std::fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("pem", &self.pem)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientCertificate {
+    /// Generate a self-signed ECDSA P-521 certificate for `localhost`,
+    /// which is what `generateCert` of go-plugin makes.
+    pub fn generate() -> Result<Self, MtlsError> {
+        use rcgen::{
+            BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+            KeyUsagePurpose,
+        };
+
+        let key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P521_SHA512)?;
+        let mut params = CertificateParams::new(vec![SERVER_NAME.to_string()])?;
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "HashiCorp");
+        params
+            .distinguished_name
+            .push(DnType::CommonName, SERVER_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+            KeyUsagePurpose::KeyCertSign,
+        ];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = params.self_signed(&key)?;
+        Ok(Self {
+            pem: cert.pem(),
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()),
+        })
+    }
+
+    /// PEM encoded certificate, which is given to the plugin
+    pub fn pem(&self) -> &str {
+        &self.pem
+    }
+}
+
+/// Accept only one certificate.
+/// The certificates of go-plugin are self-signed CAs, which webpki does not accept as end entities.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCert {
+    fn verify(&self, end_entity: &CertificateDer) -> Result<(), rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::UnknownIssuer,
+            ))
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Make the tls connection to a plugin over `stream`.
+/// `server_cert` is the DER from `HandshakeInformation::server_cert_der`.
+pub(crate) async fn connect<S>(
+    stream: S,
+    server_cert: Vec<u8>,
+    client_cert: &ClientCertificate,
+) -> Result<tokio_rustls::client::TlsStream<S>, MtlsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // go-plugin uses P-521 certificates, which only aws-lc-rs can verify.
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = Arc::new(PinnedCert {
+        cert: CertificateDer::from(server_cert),
+        algorithms: provider.signature_verification_algorithms,
+    });
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_client_auth_cert(
+            vec![client_cert.cert.clone()],
+            PrivateKeyDer::Pkcs8(client_cert.key.clone_key()),
+        )?;
+    // grpc-go rejects connections without ALPN.
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let server_name = ServerName::try_from(SERVER_NAME).expect("valid server name");
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+    use rustls::DistinguishedName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    impl ClientCertVerifier for PinnedCert {
+        fn root_hint_subjects(&self) -> &[DistinguishedName] {
+            &[]
+        }
+
+        fn verify_client_cert(
+            &self,
+            end_entity: &CertificateDer,
+            _intermediates: &[CertificateDer],
+            _now: UnixTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            self.verify(end_entity)?;
+            Ok(ClientCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.algorithms.supported_schemes()
+        }
+    }
+
+    /// Server like `plugin.Serve` with AutoMTLS, which accepts only `client_cert`.
+    fn acceptor(
+        server: &ClientCertificate,
+        client_cert: &ClientCertificate,
+    ) -> tokio_rustls::TlsAcceptor {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let verifier = Arc::new(PinnedCert {
+            cert: client_cert.cert.clone(),
+            algorithms: provider.signature_verification_algorithms,
+        });
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![server.cert.clone()],
+                PrivateKeyDer::Pkcs8(server.key.clone_key()),
+            )
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        tokio_rustls::TlsAcceptor::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let server = ClientCertificate::generate().unwrap();
+        let client = ClientCertificate::generate().unwrap();
+        assert!(client.pem().starts_with("-----BEGIN CERTIFICATE-----"));
+        let acceptor = acceptor(&server, &client);
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (client_tls, server_tls) = tokio::join!(
+            connect(client_stream, server.cert.to_vec(), &client),
+            acceptor.accept(server_stream)
+        );
+        let mut client_tls = client_tls.unwrap();
+        let mut server_tls = server_tls.unwrap();
+        assert_eq!(client_tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        client_tls.write_all(b"ping").await.unwrap();
+        client_tls.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server_tls.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_connect_wrong_cert() {
+        let server = ClientCertificate::generate().unwrap();
+        let other = ClientCertificate::generate().unwrap();
+        let client = ClientCertificate::generate().unwrap();
+
+        // The handshake gives a certificate different from the one the server uses.
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (client_tls, _) = tokio::join!(
+            connect(client_stream, other.cert.to_vec(), &client),
+            acceptor(&server, &client).accept(server_stream)
+        );
+        assert!(client_tls.is_err());
+
+        // The server does not know the client certificate.
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (_, server_tls) = tokio::join!(
+            connect(client_stream, server.cert.to_vec(), &client),
+            acceptor(&server, &other).accept(server_stream)
+        );
+        assert!(server_tls.is_err());
+    }
+}
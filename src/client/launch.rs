@@ -0,0 +1,474 @@
+use crate::client::Compression;
+use crate::{AppProtocolVersions, APP_PROTOCOL_VERSIONS_ENV, AUTH_TOKEN_ENV};
+
+/// Time to wait for the handshake information from the server.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Number of binaries kept in the remote cache.
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 8;
+
+/// Quote `s` for POSIX shell.
+pub(crate) fn shell_quote(s: &[u8]) -> Vec<u8> {
+    let mut quoted = b"'".to_vec();
+    for &c in s {
+        if c == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Run `script` with POSIX shell, whatever the login shell is.
+pub(crate) fn sh_c<S: AsRef<[u8]>>(script: S) -> Vec<u8> {
+    let mut command = b"sh -c ".to_vec();
+    command.extend_from_slice(&shell_quote(script.as_ref()));
+    command
+}
+
+/// Run the server as another user (see `LaunchSpec::privilege`).
+#[derive(Clone, PartialEq, Eq)]
+pub enum Privilege {
+    /// `sudo -n`, which fails unless sudoers allows it without password (`NOPASSWD`)
+    Sudo { user: Option<String> },
+    /// `sudo -k -S`, the password is sent as the first line of stdin, before the auth token.
+    /// sudoers must ask the password, otherwise the line goes to the server.
+    /// The binary is always written to a tmp file or the cache, not sent on stdin.
+    /// sudoers with `requiretty` is not supported, because no pty is allocated.
+    SudoPassword {
+        user: Option<String>,
+        password: String,
+    },
+    /// `runuser -u`, when the SSH login user is root
+    Runuser { user: String },
+}
+
+impl std::fmt::Debug for Privilege {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Privilege::Sudo { user } => f.debug_struct("Sudo").field("user", user).finish(),
+            Privilege::SudoPassword { user, .. } => f
+                .debug_struct("SudoPassword")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Privilege::Runuser { user } => f.debug_struct("Runuser").field("user", user).finish(),
+        }
+    }
+}
+
+impl Privilege {
+    /// `sudo -n` as root
+    pub fn sudo() -> Self {
+        Privilege::Sudo { user: None }
+    }
+
+    /// `sudo -k -S` as root with `password`
+    pub fn sudo_password<P: Into<String>>(password: P) -> Self {
+        Privilege::SudoPassword {
+            user: None,
+            password: password.into(),
+        }
+    }
+
+    /// `runuser -u <user>`
+    pub fn runuser<U: Into<String>>(user: U) -> Self {
+        Privilege::Runuser { user: user.into() }
+    }
+
+    /// Run as `user` instead of root. This has no effect on `Runuser`.
+    pub fn user<U: Into<String>>(mut self, user: U) -> Self {
+        match &mut self {
+            Privilege::Sudo { user: u } | Privilege::SudoPassword { user: u, .. } => {
+                *u = Some(user.into())
+            }
+            Privilege::Runuser { .. } => (),
+        }
+        self
+    }
+
+    /// Whether the server runs as root, who can read the files of the login user.
+    pub(crate) fn is_root(&self) -> bool {
+        match self {
+            Privilege::Sudo { user } | Privilege::SudoPassword { user, .. } => {
+                user.as_deref().is_none_or(|user| user == "root")
+            }
+            Privilege::Runuser { user } => user == "root",
+        }
+    }
+
+    /// Password to send as the first line of stdin, if any.
+    pub(crate) fn password(&self) -> Option<&str> {
+        match self {
+            Privilege::SudoPassword { password, .. } => Some(password),
+            _ => None,
+        }
+    }
+
+    /// Wrap `command` to run as the target user.
+    /// `command` is run with `sh -c`, because sudo and runuser only run a program.
+    fn wrap(&self, command: &[u8]) -> Vec<u8> {
+        let (mut wrapped, user) = match self {
+            Privilege::Sudo { user } => (b"exec sudo -n".to_vec(), user.as_deref()),
+            Privilege::SudoPassword { user, .. } => {
+                (b"exec sudo -k -S -p ''".to_vec(), user.as_deref())
+            }
+            Privilege::Runuser { user } => (b"exec runuser".to_vec(), Some(user.as_str())),
+        };
+        if let Some(user) = user {
+            wrapped.extend_from_slice(b" -u ");
+            wrapped.extend_from_slice(&shell_quote(user.as_bytes()));
+        }
+        wrapped.extend_from_slice(b" -- ");
+        wrapped.extend_from_slice(&sh_c(command));
+        wrapped
+    }
+}
+
+/// How the binary was started on remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LaunchMethod {
+    /// piped into `elfexec`
+    Elfexec,
+    /// loaded into a memfd by a perl one-liner
+    MemfdPerl,
+    /// loaded into a memfd by a python3 one-liner
+    MemfdPython3,
+    /// written to the tmp file at this absolute path (not quoted)
+    TmpFile(String),
+    /// cached at this absolute path (not quoted, see `LaunchSpec::cache`)
+    Cache(String),
+}
+
+/// How to launch the rpc server on remote
+#[derive(Debug, Clone)]
+pub struct LaunchSpec {
+    pub(crate) args: Vec<u8>,
+    pub(crate) envs: Vec<(String, String)>,
+    pub(crate) current_dir: Option<Vec<u8>>,
+    pub(crate) login_shell: bool,
+    pub(crate) stdio: bool,
+    pub(crate) auth: bool,
+    pub(crate) handshake_timeout: std::time::Duration,
+    pub(crate) cache: bool,
+    pub(crate) cache_max_entries: usize,
+    pub(crate) compression: Vec<Compression>,
+    pub(crate) staging_dirs: Vec<Vec<u8>>,
+    pub(crate) privilege: Option<Privilege>,
+}
+
+impl Default for LaunchSpec {
+    fn default() -> Self {
+        Self {
+            args: vec![],
+            envs: vec![],
+            current_dir: None,
+            login_shell: false,
+            stdio: false,
+            auth: true,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            cache: false,
+            cache_max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            compression: vec![],
+            staging_dirs: vec![],
+            privilege: None,
+        }
+    }
+}
+
+impl LaunchSpec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Arguments for the binary.
+    /// These are passed to the remote shell as is, so quote them yourself.
+    pub fn raw_args<A: Into<Vec<u8>>>(mut self, args: A) -> Self {
+        self.args = args.into();
+        self
+    }
+
+    /// Append an argument for the binary. It is quoted for the remote shell.
+    pub fn arg<A: AsRef<[u8]>>(mut self, arg: A) -> Self {
+        if !self.args.is_empty() {
+            self.args.push(b' ');
+        }
+        self.args.extend_from_slice(&shell_quote(arg.as_ref()));
+        self
+    }
+
+    /// Append arguments for the binary. They are quoted for the remote shell.
+    pub fn args<I, A>(self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<[u8]>,
+    {
+        args.into_iter().fold(self, |spec, arg| spec.arg(arg))
+    }
+
+    /// Set an environment variable for the server.
+    /// It is sent with the SSH `env` request, or with `env K=V` prefix
+    /// when the SSH server does not accept it (see `AcceptEnv` of sshd).
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set environment variables for the server.
+    pub fn envs<I, K, V>(mut self, envs: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.envs
+            .extend(envs.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Working directory of the server on remote (default: home directory).
+    pub fn current_dir<D: AsRef<[u8]>>(mut self, dir: D) -> Self {
+        self.current_dir = Some(dir.as_ref().to_vec());
+        self
+    }
+
+    /// Run the server through a login shell (`$SHELL -l`), so profile scripts
+    /// like `~/.profile` are read, e.g. to set `PATH` (default: false).
+    pub fn login_shell(mut self, login_shell: bool) -> Self {
+        self.login_shell = login_shell;
+        self
+    }
+
+    /// Advertise app protocol versions the client supports.
+    /// `transport::listen` chooses the highest one supported by both sides.
+    pub fn app_protocol_versions<V: Into<AppProtocolVersions>>(self, versions: V) -> Self {
+        self.env(APP_PROTOCOL_VERSIONS_ENV, versions.into().to_string())
+    }
+
+    /// Keep stdin of the server open, so the server can use `transport::listen_stdio`.
+    /// `elfexec` is not used because it consumes stdin, the binary goes to a memfd or a tmp file.
+    pub fn stdio(mut self, stdio: bool) -> Self {
+        self.stdio = stdio;
+        self
+    }
+
+    /// Give a random auth token to the server (default: true).
+    /// `transport::listen` rejects connections without the token.
+    /// Disable this for servers not using this crate, like go-plugin plugins.
+    pub fn auth(mut self, auth: bool) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Time to wait for the handshake information (default: `DEFAULT_HANDSHAKE_TIMEOUT`).
+    /// This includes the time to start the server, but not the upload of the binary.
+    pub fn handshake_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Keep the binary in `~/.cache/sshrpc/<sha256>` on remote (default: false).
+    /// The binary is uploaded only when it is not cached yet,
+    /// and its hash is verified after the upload.
+    /// This is ignored when `privilege` runs the server as a user other than root,
+    /// who cannot read the home directory of the login user.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Number of binaries kept in the cache (default: `DEFAULT_CACHE_MAX_ENTRIES`).
+    /// Least recently used ones are removed, but the binary being launched is always kept.
+    pub fn cache_max_entries(mut self, max_entries: usize) -> Self {
+        self.cache_max_entries = max_entries;
+        self
+    }
+
+    /// Compress the binary while uploading (default: no compression).
+    /// The first algorithm in `preferred` which is enabled by the crate features
+    /// and whose tool is found on remote is used. If none is found, the binary is sent as is.
+    pub fn compression<I: IntoIterator<Item = Compression>>(mut self, preferred: I) -> Self {
+        self.compression = preferred.into_iter().collect();
+        self
+    }
+
+    /// Add a candidate directory to write the binary to, when it cannot be run
+    /// from memory (no `elfexec`, perl or python3 on remote).
+    /// Candidates are tried in order, skipping ones where files cannot be created or
+    /// executed (e.g. `noexec` mounts). If none is given, `/dev/shm`, `$HOME` and
+    /// `$TMPDIR` (or `/tmp`) are tried, or only `/dev/shm` and `/tmp` when `privilege`
+    /// runs the server as a user other than root.
+    /// With such a `privilege`, the directory must be accessible to that user.
+    pub fn staging_dir<D: AsRef<[u8]>>(mut self, dir: D) -> Self {
+        self.staging_dirs.push(dir.as_ref().to_vec());
+        self
+    }
+
+    /// Run the server as another user, usually root (default: the SSH login user).
+    /// When the target user is not root, the binary is made readable by everyone when it is
+    /// written to a tmp file, so the target user can run it, and the home directory of the
+    /// login user is not used (see `cache` and `staging_dir`).
+    /// The tmp file is removed by the login user.
+    /// Environment variables are always given with `env K=V` prefix,
+    /// because sudo resets the environment.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = Some(privilege);
+        self
+    }
+
+    /// Build the command line to run `program` on remote shell.
+    /// When `auth` is enabled, the command reads the auth token from the first line of stdin.
+    /// If `input_filter` is given, stdin of `program` is piped through it.
+    pub(crate) fn command(&self, program: &[u8], input_filter: Option<&str>) -> Vec<u8> {
+        let command = self.command_without_privilege(program, input_filter);
+        let command = match &self.privilege {
+            Some(privilege) => privilege.wrap(&command),
+            None => command,
+        };
+        if !self.login_shell {
+            return command;
+        }
+        let mut script = b"exec \"${SHELL:-/bin/sh}\" -l -c ".to_vec();
+        script.extend_from_slice(&shell_quote(&command));
+        sh_c(script)
+    }
+
+    fn command_without_privilege(&self, program: &[u8], input_filter: Option<&str>) -> Vec<u8> {
+        let mut command = vec![];
+        if let Some(dir) = &self.current_dir {
+            command.extend_from_slice(b"cd ");
+            command.extend_from_slice(&shell_quote(dir));
+            command.extend_from_slice(b" && ");
+        }
+        if let Some(filter) = input_filter {
+            command.extend_from_slice(filter.as_bytes());
+            command.extend_from_slice(b" | ");
+        }
+        if self.auth {
+            command.extend_from_slice(b"exec ");
+        }
+        command.extend_from_slice(&self.command_without_auth(program));
+        if !self.auth {
+            return command;
+        }
+        let mut inner = format!("IFS= read -r {0} && export {0} && ", AUTH_TOKEN_ENV).into_bytes();
+        inner.extend_from_slice(&command);
+        sh_c(inner)
+    }
+
+    /// Same as `self`, but without environment variables.
+    /// Used when they are set by the SSH `env` request instead.
+    pub(crate) fn without_envs(&self) -> Self {
+        Self {
+            envs: vec![],
+            ..self.clone()
+        }
+    }
+
+    fn command_without_auth(&self, program: &[u8]) -> Vec<u8> {
+        let mut command = vec![];
+        if !self.envs.is_empty() {
+            command.extend_from_slice(b"env");
+            for (key, value) in &self.envs {
+                command.push(b' ');
+                command.extend_from_slice(&shell_quote(format!("{}={}", key, value).as_bytes()));
+            }
+            command.push(b' ');
+        }
+        command.extend_from_slice(program);
+        if !self.args.is_empty() {
+            command.push(b' ');
+            command.extend_from_slice(&self.args);
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            LaunchSpec::new().auth(false).command(b"elfexec", None),
+            b"elfexec".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .raw_args("-v --name 'x y'")
+                .env("KEY", "it's")
+                .command(b"/tmp/tmp.x", None),
+            b"env 'KEY=it'\\''s' /tmp/tmp.x -v --name 'x y'".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new().raw_args("'x'").command(b"elfexec", None),
+            b"sh -c 'IFS= read -r SSHRPC_AUTH_TOKEN && export SSHRPC_AUTH_TOKEN && exec elfexec '\\''x'\\'''"
+                .to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .command(b"elfexec", Some("zstd -dc")),
+            b"zstd -dc | elfexec".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new().command(b"elfexec", Some("zstd -dc")),
+            b"sh -c 'IFS= read -r SSHRPC_AUTH_TOKEN && export SSHRPC_AUTH_TOKEN && zstd -dc | exec elfexec'"
+                .to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .arg("-v")
+                .args(["x y", "it's"])
+                .current_dir("/tmp/a b")
+                .command(b"/tmp/tmp.x", None),
+            b"cd '/tmp/a b' && /tmp/tmp.x '-v' 'x y' 'it'\\''s'".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new().current_dir("/srv").command(b"elfexec", None),
+            b"sh -c 'IFS= read -r SSHRPC_AUTH_TOKEN && export SSHRPC_AUTH_TOKEN && cd '\\''/srv'\\'' && exec elfexec'"
+                .to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .login_shell(true)
+                .command(b"elfexec", None),
+            b"sh -c 'exec \"${SHELL:-/bin/sh}\" -l -c '\\''elfexec'\\'''".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .privilege(Privilege::sudo().user("app"))
+                .command(b"/tmp/tmp.x", None),
+            b"exec sudo -n -u 'app' -- sh -c '/tmp/tmp.x'".to_vec()
+        );
+        assert_eq!(
+            LaunchSpec::new()
+                .auth(false)
+                .privilege(Privilege::sudo_password("secret"))
+                .command(b"/tmp/tmp.x", None),
+            b"exec sudo -k -S -p '' -- sh -c '/tmp/tmp.x'".to_vec()
+        );
+        // sudo reads the password line, then the wrapped shell reads the token line
+        assert_eq!(
+            LaunchSpec::new()
+                .privilege(Privilege::sudo_password("secret"))
+                .command(b"/tmp/tmp.x", None),
+            b"exec sudo -k -S -p '' -- sh -c 'sh -c '\\''IFS= read -r SSHRPC_AUTH_TOKEN && export SSHRPC_AUTH_TOKEN && exec /tmp/tmp.x'\\'''"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_privilege_is_root() {
+        assert!(Privilege::sudo().is_root());
+        assert!(Privilege::sudo_password("secret").user("root").is_root());
+        assert!(!Privilege::sudo().user("app").is_root());
+        assert!(!Privilege::runuser("app").is_root());
+    }
+}
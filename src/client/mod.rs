@@ -1,7 +1,26 @@
+pub mod command;
+pub mod compress;
+pub mod connect;
+pub mod embed;
+pub mod exit;
+pub mod go_plugin;
+pub mod launch;
 pub mod russh;
+pub mod supervise;
+pub mod target;
+#[cfg(test)]
+pub(crate) mod test_server;
 
-use crate::transport::stream2transport;
-use crate::HandshakeInformation;
+pub use compress::Compression;
+pub use connect::{ConnectBuilder, SessionGuard};
+pub use embed::EmbeddedBinaries;
+pub use exit::{ExitStatus, KillError, ServerExit};
+pub use launch::{LaunchMethod, LaunchSpec, Privilege};
+pub use target::RemoteTarget;
+
+use crate::auth::token_frame;
+use crate::transport::BincodeTransport;
+use crate::{AppProtocolVersions, HandshakeInformation, NetworkType, Protcol};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -12,54 +31,278 @@ where
 {
     pub handshake_information: HandshakeInformation,
     /// channel of execution or other stuff
-    pub channel: C,
-    /// stream of tcp channel (This is usually a port forward)
+    /// This is `None` when the channel of execution itself is used as `stream` (stdio mode).
+    pub channel: Option<C>,
+    /// stream of forwarded channel (This is usually a tcp port forward or a streamlocal forward)
+    /// In stdio mode, this is the stdin/stdout of the server.
+    /// `handshake_information.network_type` records which one was negotiated.
     pub stream: S,
+    /// auth token given to the server
+    /// This is sent as the first frame of `stream` by `try_into_transport`.
+    pub auth_token: Option<String>,
+    /// how the binary was started by `SshRpcExt::exec_rpc_server`
+    /// This is `None` when the session is made by `read_handshake_information`.
+    pub launch_method: Option<LaunchMethod>,
 }
 
+/// channel of execution (if any) and the transport made by `SshRpcSession::try_into_transport`
+pub type IntoTransport<C, S, Item, SinkItem> = (Option<C>, BincodeTransport<S, Item, SinkItem>);
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum AppProtocolError {
-    #[error("App protocol version mismatch: expected {expected}, got {got}")]
-    VersionMismatch { expected: u32, got: u32 },
+    #[error("App protocol version mismatch: expected one of {expected}, got {got}")]
+    VersionMismatch {
+        expected: AppProtocolVersions,
+        got: u32,
+    },
+    #[error("Protocol mismatch: expected {expected}, got {got}")]
+    ProtocolMismatch { expected: Protcol, got: Protcol },
+    #[error(
+        "Server requires AutoMTLS, use `try_into_grpc_channel_mtls` with the client certificate"
+    )]
+    TlsRequired,
+}
+
+/// Error of `SshRpcSession::try_into_transport`.
+/// The session is returned intact, so the caller can fall back to something else.
+pub struct IntoTransportError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub error: AppProtocolError,
+    pub session: Box<SshRpcSession<C, S>>,
+}
+
+impl<C, S> std::fmt::Debug for IntoTransportError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("IntoTransportError")
+            .field("error", &self.error)
+            .field("handshake_information", &self.session.handshake_information)
+            .finish()
+    }
+}
+
+impl<C, S> std::fmt::Display for IntoTransportError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl<C, S> std::error::Error for IntoTransportError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[derive(thiserror::Error)]
+#[error("{0}")]
+pub enum IntoGrpcChannelError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    AppProtocolError(#[from] IntoTransportError<C, S>),
+    TransportError(#[from] tonic::transport::Error),
+    IoError(#[from] std::io::Error),
+    #[cfg(feature = "auto-mtls")]
+    MtlsError(#[from] go_plugin::MtlsError),
+}
+
+#[cfg(feature = "grpc")]
+impl<C, S> std::fmt::Debug for IntoGrpcChannelError<C, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IntoGrpcChannelError::AppProtocolError(e) => e.fmt(f),
+            IntoGrpcChannelError::TransportError(e) => e.fmt(f),
+            IntoGrpcChannelError::IoError(e) => e.fmt(f),
+            #[cfg(feature = "auto-mtls")]
+            IntoGrpcChannelError::MtlsError(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Make a tarpc transport over `stream`, which sends `auth_token_frame` first.
+pub(crate) fn authed_transport<S, Item, SinkItem>(
+    stream: S,
+    auth_token_frame: Option<Vec<u8>>,
+) -> BincodeTransport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let mut framed =
+        tokio_util::codec::Framed::new(stream, tokio_util::codec::LengthDelimitedCodec::new());
+    if let Some(frame) = auth_token_frame {
+        // This is flushed with the first request.
+        framed.write_buffer_mut().extend_from_slice(&frame);
+    }
+    tarpc::serde_transport::new(framed, tarpc::tokio_serde::formats::Bincode::default())
+}
+
+/// Make a tonic channel over `io`.
+#[cfg(feature = "grpc")]
+async fn grpc_channel<IO>(io: IO) -> Result<tonic::transport::Channel, tonic::transport::Error>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let io = std::sync::Arc::new(std::sync::Mutex::new(Some(io)));
+    let connector = tower::service_fn(move |_: tonic::transport::Uri| {
+        let io = io.lock().unwrap().take();
+        async move {
+            io.map(hyper_util::rt::TokioIo::new).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "forwarded stream is already used",
+                )
+            })
+        }
+    });
+    // The uri is not used for connecting, the forwarded stream is used instead.
+    tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_with_connector(connector)
+        .await
 }
 
 impl<C, S> SshRpcSession<C, S>
 where
     S: AsyncRead + AsyncWrite,
 {
+    fn check_app_protocol(
+        self,
+        protcol: Protcol,
+        app_protocol_version: AppProtocolVersions,
+    ) -> Result<Self, IntoTransportError<C, S>> {
+        let error = if self.handshake_information.protcol != protcol {
+            AppProtocolError::ProtocolMismatch {
+                expected: protcol,
+                got: self.handshake_information.protcol,
+            }
+        } else if !app_protocol_version.contains(self.handshake_information.app_protocol_version) {
+            AppProtocolError::VersionMismatch {
+                expected: app_protocol_version,
+                got: self.handshake_information.app_protocol_version,
+            }
+        } else {
+            return Ok(self);
+        };
+        Err(IntoTransportError {
+            error,
+            session: Box::new(self),
+        })
+    }
+
+    /// The auth token to send on the stream.
+    /// In stdio mode, nobody else can connect to the server, so it is not needed.
+    fn auth_token_frame(&self) -> Option<Vec<u8>> {
+        if self.handshake_information.network_type == NetworkType::Stdio {
+            return None;
+        }
+        self.auth_token.as_deref().map(token_frame)
+    }
+
+    /// Make a tarpc transport over the forwarded stream.
+    /// `app_protocol_version` can be a set of versions the client supports.
+    /// The negotiated version is `handshake_information.app_protocol_version`.
     pub fn try_into_transport<Item, SinkItem>(
         self,
-        app_protocol_version: u32,
-    ) -> Result<
-        (
-            C,
-            tarpc::serde_transport::Transport<
-                S,
-                Item,
-                SinkItem,
-                tarpc::tokio_serde::formats::Bincode<Item, SinkItem>,
-            >,
-        ),
-        AppProtocolError,
-    >
+        app_protocol_version: impl Into<AppProtocolVersions>,
+    ) -> Result<IntoTransport<C, S, Item, SinkItem>, IntoTransportError<C, S>>
     where
         Item: for<'de> Deserialize<'de>,
         SinkItem: Serialize,
     {
         // impl Stream<Item = Result<Item, std::io::Error>> + Sink<SinkItem>
-        if self.handshake_information.app_protocol_version != app_protocol_version {
-            return Err(
-                AppProtocolError::VersionMismatch {
-                expected: app_protocol_version,
-                got: self.handshake_information.app_protocol_version,
-            });
+        let session =
+            self.check_app_protocol(Protcol::TarpcBincode, app_protocol_version.into())?;
+        let auth_token_frame = session.auth_token_frame();
+        let transport = authed_transport(session.stream, auth_token_frame);
+        Ok((session.channel, transport))
+    }
+
+    /// Make a tonic channel over the forwarded stream.
+    /// The server must be launched with `transport::serve_grpc` or `transport::listen_grpc`.
+    /// Only one connection is made, so the channel cannot reconnect.
+    /// The channel is plaintext, so a server answering with a certificate (go-plugin AutoMTLS)
+    /// is rejected with `AppProtocolError::TlsRequired`.
+    #[cfg(feature = "grpc")]
+    pub async fn try_into_grpc_channel(
+        self,
+        app_protocol_version: impl Into<AppProtocolVersions>,
+    ) -> Result<(Option<C>, tonic::transport::Channel), IntoGrpcChannelError<C, S>>
+    where
+        S: Send + Unpin + 'static,
+    {
+        let session = self.check_app_protocol(Protcol::Grpc, app_protocol_version.into())?;
+        if session.handshake_information.server_cert.is_some() {
+            return Err(IntoTransportError {
+                error: AppProtocolError::TlsRequired,
+                session: Box::new(session),
+            }
+            .into());
         }
-        let transport = stream2transport(self.stream);
-        Ok((self.channel, transport))
+        let session = session.send_auth_token().await?;
+        let channel = grpc_channel(session.stream).await?;
+        Ok((session.channel, channel))
+    }
+
+    /// Make a tonic channel over the forwarded stream with go-plugin AutoMTLS.
+    /// `client_cert` must be the one given to `GoPluginConfig::auto_mtls`.
+    /// The server certificate from the handshake is the only one accepted.
+    /// A server answering without a certificate is connected in plaintext.
+    #[cfg(feature = "auto-mtls")]
+    pub async fn try_into_grpc_channel_mtls(
+        self,
+        app_protocol_version: impl Into<AppProtocolVersions>,
+        client_cert: &go_plugin::ClientCertificate,
+    ) -> Result<(Option<C>, tonic::transport::Channel), IntoGrpcChannelError<C, S>>
+    where
+        S: Send + Unpin + 'static,
+    {
+        let session = self.check_app_protocol(Protcol::Grpc, app_protocol_version.into())?;
+        let server_cert = match session.handshake_information.server_cert_der() {
+            Some(cert) => cert.map_err(go_plugin::MtlsError::from)?,
+            None => {
+                let session = session.send_auth_token().await?;
+                let channel = grpc_channel(session.stream).await?;
+                return Ok((session.channel, channel));
+            }
+        };
+        let session = session.send_auth_token().await?;
+        let stream = go_plugin::mtls::connect(session.stream, server_cert, client_cert).await?;
+        let channel = grpc_channel(stream).await?;
+        Ok((session.channel, channel))
+    }
+
+    /// Write the auth token frame to the stream for the gRPC server.
+    #[cfg(feature = "grpc")]
+    async fn send_auth_token(mut self) -> std::io::Result<Self>
+    where
+        S: Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        if let Some(frame) = self.auth_token_frame() {
+            self.stream.write_all(&frame).await?;
+        }
+        Ok(self)
     }
 }
 
 /// launch rpc server on remote ssh server
+#[allow(async_fn_in_trait)]
 pub trait SshRpcExt<C, S>
 where
     S: AsyncRead + AsyncWrite,
@@ -69,7 +312,8 @@ where
     /// Asynchronously executes a remote procedure call server on a remote SSH server.
     /// This function launches a specified binary program with provided arguments on the remote host.
     /// The `binary` program must write `HandshakeInformation` to stdout on the first line.
-    /// This function parses this line and then creates an SSH port forward.
+    /// This function parses this line and then creates an SSH port forward
+    /// (`direct-tcpip` for tcp, `direct-streamlocal@openssh.com` for unix domain sockets).
     ///
     /// # Parameters
     /// - `binary`: A type that implements `AsyncRead` and `Unpin`, representing the executable binary.
@@ -84,11 +328,66 @@ where
     ) -> Result<SshRpcSession<C, S>, Self::Error>
     where
         R: tokio::io::AsyncRead + Unpin,
-        A: Into<Vec<u8>>;
+        A: Into<Vec<u8>>,
+    {
+        self.exec_rpc_server_with(binary, &LaunchSpec::new().raw_args(args))
+            .await
+    }
+
+    /// Same as `exec_rpc_server`, but keeps stdin of the server open,
+    /// so the server can use `transport::listen_stdio` instead of listening on a port.
+    /// `elfexec` is not used because it consumes stdin, the binary goes to a memfd or a tmp file.
+    async fn exec_rpc_server_stdio<R, A>(
+        &self,
+        binary: R,
+        args: A,
+    ) -> Result<SshRpcSession<C, S>, Self::Error>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+        A: Into<Vec<u8>>,
+    {
+        self.exec_rpc_server_with(binary, &LaunchSpec::new().raw_args(args).stdio(true))
+            .await
+    }
+
+    /// Same as `exec_rpc_server`, but launches the binary as described by `spec`.
+    async fn exec_rpc_server_with<R>(
+        &self,
+        binary: R,
+        spec: &LaunchSpec,
+    ) -> Result<SshRpcSession<C, S>, Self::Error>
+    where
+        R: tokio::io::AsyncRead + Unpin;
+
+    /// Detect the os, architecture and libc of the remote host.
+    async fn remote_target(&self) -> Result<RemoteTarget, Self::Error>;
+
+    /// Same as `exec_rpc_server_with`, but chooses the binary for the remote host
+    /// from `binaries`, a map from target triple (e.g. `aarch64-unknown-linux-musl`) to binary.
+    async fn exec_rpc_server_multiarch<I, K, R>(
+        &self,
+        binaries: I,
+        spec: &LaunchSpec,
+    ) -> Result<SshRpcSession<C, S>, Self::Error>
+    where
+        I: IntoIterator<Item = (K, R)>,
+        K: AsRef<str>,
+        R: tokio::io::AsyncRead + Unpin;
 
     /// Read handshake information from channel and return SshRpcSession from it.
+    /// This waits `launch::DEFAULT_HANDSHAKE_TIMEOUT` at most.
     async fn read_handshake_information(
         &self,
         channel: C,
+    ) -> Result<SshRpcSession<C, S>, Self::Error> {
+        self.read_handshake_information_timeout(channel, launch::DEFAULT_HANDSHAKE_TIMEOUT)
+            .await
+    }
+
+    /// Same as `read_handshake_information`, but with the `timeout`.
+    async fn read_handshake_information_timeout(
+        &self,
+        channel: C,
+        timeout: std::time::Duration,
     ) -> Result<SshRpcSession<C, S>, Self::Error>;
 }
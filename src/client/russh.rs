@@ -1,8 +1,26 @@
-use crate::client::{SshRpcExt, SshRpcSession};
-use crate::HandshakeInformation;
+mod cache;
+mod command;
+mod memfd;
+mod sftp;
+mod staging;
+
+pub(crate) use staging::remove_tmpfile;
+pub use staging::{StagingDirError, StagingFailReason};
+
+use crate::auth::{generate_token, token_frame};
+use crate::client::authed_transport;
+use crate::client::command::{CommandError, CommandExt};
+use crate::client::compress::{self, encoder, Compression};
+use crate::client::launch::{sh_c, shell_quote};
+use crate::client::target::PROBE_SCRIPT;
+use crate::client::{LaunchMethod, LaunchSpec, Privilege, RemoteTarget, SshRpcExt, SshRpcSession};
+use crate::transport::BincodeTransport;
+use crate::{HandshakeInformation, NetworkAddr, UNLINK_SELF_ENV};
 use russh::client::{Handle, Handler, Msg};
 
 use russh::{Channel, ChannelMsg, ChannelStream};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
 use tracing::{debug, error, warn};
 
 fn drop_last_newline(s: &str) -> &str {
@@ -24,6 +42,11 @@ fn trace_msg(command: &str, msg: &ChannelMsg) {
         ChannelMsg::ExitStatus { exit_status } => {
             debug!("{}:exit: Exit status: {:?}", command, exit_status);
         }
+        ChannelMsg::ExitSignal { .. } => {
+            if let Some(exit) = crate::client::ExitStatus::from_msg(msg) {
+                warn!("{}:exit: {}", command, exit);
+            }
+        }
         _ => (),
     }
 }
@@ -42,117 +65,311 @@ async fn wait_until_exit(command: &str, mut channel: Channel<Msg>) -> (Channel<M
     (channel, code)
 }
 
-#[derive(Debug)]
-enum ExitStatus {
-    Code(u32),
-    Processing,
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub enum RpcStartError {
+    IoError(#[from] std::io::Error),
+    #[error("Handshake information not received")]
+    HandshakeInformationNotReceived,
+    #[error("Handshake information not received in time: output={output:?}")]
+    HandshakeTimeout {
+        output: String,
+    },
+    #[error("Failed to get handshake information: {0}")]
+    InvalidHandshakeInformation(String),
+    #[error("Failed to launch: status code={0}")]
+    LaunchFail(u32),
+    #[error("Failed to launch: {0}")]
+    LaunchKilled(crate::client::ExitStatus),
+    #[error("Failed to upload binary to cache: sha256={0}")]
+    CacheUploadFail(String),
+    #[error("No usable staging directory: {}", format_staging_dir_errors(.0))]
+    StagingDirUnavailable(Vec<StagingDirError>),
+    #[error("Failed to detect remote target: output={0:?}")]
+    RemoteTargetDetectionFail(String),
+    #[error("No binary for remote target {target}: available={available:?}")]
+    NoMatchingBinary {
+        target: RemoteTarget,
+        available: Vec<String>,
+    },
+    ParseHandshakeInformation(#[from] crate::ParseHandshakeError),
+    RusshError(#[from] russh::Error),
+    CommandError(#[from] CommandError),
+    SftpError(#[from] russh_sftp::client::error::Error),
+}
+
+fn format_staging_dir_errors(errors: &[StagingDirError]) -> String {
+    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    errors.join(", ")
 }
 
-impl ExitStatus {
-    fn sucess(&self) -> bool {
-        match self {
-            ExitStatus::Code(code) => *code == 0,
-            ExitStatus::Processing => false,
+/// Find the handshake line in `buf[*consumed..]`.
+/// Lines not looking like a handshake (banner, motd of shell rc files) are skipped.
+/// `consumed` is moved to the end of the last complete line read.
+fn find_handshake_line(
+    buf: &[u8],
+    consumed: &mut usize,
+) -> Result<Option<HandshakeInformation>, crate::ParseHandshakeError> {
+    while let Some(pos) = buf[*consumed..].iter().position(|&c| c == b'\n') {
+        let line = &buf[*consumed..*consumed + pos];
+        *consumed += pos + 1;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        // core protocol version is always 1
+        if line.starts_with("1|") {
+            return line.parse().map(Some);
         }
+        debug!("skip before handshake: {}", line);
     }
+    Ok(None)
+}
 
-    fn code(&self) -> Option<u32> {
-        match self {
-            ExitStatus::Code(code) => Some(*code),
-            ExitStatus::Processing => None,
+/// Open a forwarded channel to `addr` of the server.
+async fn open_forward<H: Handler>(
+    handle: &Handle<H>,
+    addr: &NetworkAddr,
+) -> Result<Channel<Msg>, RpcStartError> {
+    Ok(match addr {
+        NetworkAddr::Tcp(addr) => {
+            handle
+                .channel_open_direct_tcpip(
+                    addr.ip().to_string(),
+                    addr.port() as u32,
+                    "localhost",
+                    0,
+                )
+                .await?
         }
-    }
+        NetworkAddr::Unix(path) => {
+            handle
+                .channel_open_direct_streamlocal(path.to_string_lossy())
+                .await?
+        }
+        NetworkAddr::Stdio => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "stdio mode has no address to connect",
+            )
+            .into())
+        }
+    })
 }
 
-#[derive(Debug)]
-struct Output {
-    stdout: Vec<u8>,
-    stderr: Vec<u8>,
-    code: ExitStatus,
+/// Opens more forwarded streams to a running server, so several clients can share it.
+/// The server must accept many connections, like `transport::listen` does.
+pub struct Connector<H: Handler> {
+    handle: Arc<Handle<H>>,
+    handshake_information: Arc<HandshakeInformation>,
+    auth_token: Option<String>,
 }
 
-trait OutputExt {
-    type Error;
-    async fn output<A: Into<Vec<u8>>>(&self, command: A) -> Result<Output, Self::Error>;
+impl<H: Handler> Clone for Connector<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            handshake_information: self.handshake_information.clone(),
+            auth_token: self.auth_token.clone(),
+        }
+    }
 }
 
-impl<H> OutputExt for Handle<H>
-where
-    H: Handler,
-{
-    type Error = russh::Error;
-    async fn output<A: Into<Vec<u8>>>(&self, command: A) -> Result<Output, Self::Error> {
-        let mut channel = self.channel_open_session().await?;
-        channel.exec(true, command).await?;
+impl<H: Handler> Connector<H> {
+    /// Make a connector for the server of `session`, launched through `handle`.
+    /// Returns `None` in stdio mode, where the server has only one stream.
+    pub fn new<C, S>(handle: Arc<Handle<H>>, session: &SshRpcSession<C, S>) -> Option<Self>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        if session.handshake_information.network_addr == NetworkAddr::Stdio {
+            return None;
+        }
+        Some(Self {
+            handle,
+            handshake_information: Arc::new(session.handshake_information.clone()),
+            auth_token: session.auth_token.clone(),
+        })
+    }
 
-        let mut stdout = vec![];
-        let mut stderr = vec![];
-        let mut code = ExitStatus::Processing;
-        loop {
-            let Some(msg) = channel.wait().await else {
-                break;
-            };
-            match msg {
-                ChannelMsg::Data { ref data } => {
-                    stdout.extend_from_slice(data);
-                }
-                ChannelMsg::ExtendedData { ref data, ext: 1 } => {
-                    stderr.extend_from_slice(data);
-                }
-                ChannelMsg::ExitStatus { exit_status } => {
-                    code = ExitStatus::Code(exit_status);
-                }
-                _ => {}
+    pub fn handshake_information(&self) -> &HandshakeInformation {
+        &self.handshake_information
+    }
+
+    /// Open a new forwarded stream as a session,
+    /// to use `SshRpcSession::try_into_transport` or `try_into_grpc_channel` on it.
+    pub async fn open_session(
+        &self,
+    ) -> Result<SshRpcSession<Channel<Msg>, ChannelStream<Msg>>, RpcStartError> {
+        let channel = open_forward(&self.handle, &self.handshake_information.network_addr).await?;
+        Ok(SshRpcSession {
+            handshake_information: (*self.handshake_information).clone(),
+            channel: None,
+            stream: channel.into_stream(),
+            auth_token: self.auth_token.clone(),
+            launch_method: None,
+        })
+    }
+
+    /// Open a new forwarded stream and make a tarpc transport over it.
+    /// The app protocol is not checked again, do it on the first session.
+    pub async fn connect<Item, SinkItem>(
+        &self,
+    ) -> Result<BincodeTransport<ChannelStream<Msg>, Item, SinkItem>, RpcStartError>
+    where
+        Item: for<'de> serde::Deserialize<'de>,
+        SinkItem: serde::Serialize,
+    {
+        let channel = open_forward(&self.handle, &self.handshake_information.network_addr).await?;
+        let frame = self.auth_token.as_deref().map(token_frame);
+        Ok(authed_transport(channel.into_stream(), frame))
+    }
+}
+
+/// Send the sudo password and the auth token as the first lines of stdin.
+/// See `LaunchSpec::command`.
+async fn send_auth_token(
+    channel: &Channel<Msg>,
+    spec: &LaunchSpec,
+    auth_token: Option<&str>,
+) -> Result<(), russh::Error> {
+    if let Some(password) = spec.privilege.as_ref().and_then(Privilege::password) {
+        channel.data(format!("{}\n", password).as_bytes()).await?;
+    }
+    if let Some(token) = auth_token {
+        channel.data(format!("{}\n", token).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Set `envs` with the SSH `env` request.
+/// Returns false if the server refuses any of them (sshd accepts only `AcceptEnv` ones).
+async fn request_envs(
+    channel: &mut Channel<Msg>,
+    envs: &[(String, String)],
+) -> Result<bool, RpcStartError> {
+    for (key, value) in envs {
+        channel.set_env(true, key, value).await?;
+    }
+    let mut accepted = true;
+    for _ in envs {
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => (),
+            Some(ChannelMsg::Failure) => accepted = false,
+            msg => {
+                debug!("unexpected reply to env request: {:?}", msg);
+                return Ok(false);
             }
         }
-        Ok(Output {
-            stdout: stdout.strip_suffix(b"\n").unwrap_or(&stdout).to_vec(),
-            stderr: stderr.strip_suffix(b"\n").unwrap_or(&stderr).to_vec(),
-            code,
-        })
     }
+    Ok(accepted)
 }
 
-struct CleanupGuard {
-    inner: Channel<Msg>,
+/// Open a session and run `program` as described by `spec`.
+/// Environment variables go through the SSH `env` request if possible, `env K=V` prefix otherwise.
+async fn exec_server<H: Handler>(
+    handle: &Handle<H>,
+    spec: &LaunchSpec,
+    program: &[u8],
+    input_filter: Option<&str>,
+) -> Result<Channel<Msg>, RpcStartError> {
+    let mut channel = handle.channel_open_session().await?;
+    // sudo resets the environment
+    let command = if !spec.envs.is_empty()
+        && spec.privilege.is_none()
+        && request_envs(&mut channel, &spec.envs).await?
+    {
+        debug!("environment variables are set by env request");
+        spec.without_envs().command(program, input_filter)
+    } else {
+        spec.command(program, input_filter)
+    };
+    channel.exec(true, command).await?;
+    Ok(channel)
 }
 
-impl Drop for CleanupGuard {
-    fn drop(&mut self) {
-        debug!("CleanupGuard: drop");
-        let _ = futures::executor::block_on(self.inner.eof());
+/// Choose the compression from `preferred` which is available on remote too.
+async fn negotiate_compression<H: Handler>(
+    handle: &Handle<H>,
+    preferred: &[Compression],
+) -> Result<Option<Compression>, RpcStartError> {
+    if !preferred.iter().any(Compression::is_supported) {
+        return Ok(None);
     }
+    let probe = handle
+        .output(sh_c(compress::probe_command(preferred)))
+        .await?;
+    let compression = compress::choose(preferred, &String::from_utf8_lossy(&probe.stdout));
+    debug!("compression: {:?}", compression);
+    Ok(compression)
 }
 
-impl CleanupGuard {
-    fn new(inner: Channel<Msg>) -> Self {
-        Self { inner }
+/// Write binary to a tmp file on remote and make it executable.
+/// SFTP is preferred, so neither the shell nor quoting is involved in writing.
+/// The tmp file is made by the staging script either way, which needs the shell (see `sftp`).
+/// The server removes the file after start, and files of crashed runs are swept on the next launch.
+/// Returns the absolute path, not quoted.
+async fn upload_tmpfile<H, R>(
+    handle: &Handle<H>,
+    binary: &mut R,
+    compression: Option<Compression>,
+    staging_dirs: &[Vec<u8>],
+    shared: bool,
+) -> Result<String, RpcStartError>
+where
+    H: Handler,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    // create tempfile in the first directory where files can be executed
+    let path = staging::create_tmpfile(handle, staging_dirs, shared).await?;
+    let tmpfile = shell_quote(path.as_bytes());
+
+    // the remote decompressor needs the shell
+    if compression.is_none() {
+        if let Some(upload) = sftp::SftpUpload::open(handle, &path, shared).await {
+            upload.write(binary).await?;
+            return Ok(path);
+        }
+        debug!("sftp is unavailable, fall back to shell");
     }
 
-    fn spawn(mut self) {
-        tokio::spawn(async move {
-            loop {
-                let Some(msg) = self.inner.wait().await else {
-                    break;
-                };
-                trace_msg("CleanupGuard", &msg);
-            }
-        });
+    // copy
+    let mut command = compression
+        .map_or("cat".to_string(), |c| c.decompress_command())
+        .into_bytes();
+    command.extend_from_slice(b" > ");
+    command.extend_from_slice(&tmpfile);
+    let channel = handle.channel_open_session().await?;
+    channel.exec(true, command).await?;
+    tokio::io::copy(
+        &mut encoder(binary, compression),
+        &mut channel.make_writer(),
+    )
+    .await?;
+    channel.eof().await?;
+
+    let (_, status) = wait_until_exit("copy", channel).await;
+    if status != Some(0) {
+        return Err(RpcStartError::LaunchFail(status.unwrap_or(1)));
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-#[error("{0}")]
-pub enum RpcStartError {
-    IoError(#[from] std::io::Error),
-    #[error("Handshake information not received")]
-    HandshakeInformationNotReceived,
-    #[error("Failed to get handshake information: {0}")]
-    InvalidHandshakeInformation(String),
-    #[error("Failed to launch: status code={0}")]
-    LaunchFail(u32),
-    ParseHandshakeInformation(#[from] crate::ParseHandshakeError),
-    RusshError(#[from] russh::Error),
+    // chmod, readable by everyone when another user runs it
+    let mut command = if shared {
+        b"chmod 755 ".to_vec()
+    } else {
+        b"chmod +x ".to_vec()
+    };
+    command.extend_from_slice(&tmpfile);
+    let chmod = handle.output(command).await?;
+    if !chmod.success() {
+        error!("chmod: {}", String::from_utf8_lossy(&chmod.stderr));
+        error!("chmod: status={:?}", chmod.status);
+        let code = chmod
+            .status
+            .as_ref()
+            .and_then(crate::client::ExitStatus::code);
+        return Err(RpcStartError::LaunchFail(code.unwrap_or(1)));
+    }
+
+    Ok(path)
 }
 
 /// Implementation of `SshRpcExt` for `russh`
@@ -163,126 +380,320 @@ where
     type Error = RpcStartError;
 
     /// Implementation of `SshRpcExt` for `russh`
-    async fn exec_rpc_server<R, A>(
+    async fn exec_rpc_server_with<R>(
         &self,
         mut binary: R,
-        args: A,
+        spec: &LaunchSpec,
     ) -> Result<SshRpcSession<Channel<Msg>, ChannelStream<Msg>>, Self::Error>
     where
         R: tokio::io::AsyncRead + Unpin,
-        A: Into<Vec<u8>>,
     {
-        debug!("which elfexec on remote");
-        let has_elfexec = self.output(b"which elfexec").await?.code.sucess();
-
-        let channel = if has_elfexec {
-            debug!("elfexec is available. using it");
-            let mut command = b"elfexec ".to_vec();
-            command.extend_from_slice(&args.into());
+        // sudo -S reads the password line from stdin before the auth token line.
+        // If sudo rejects the password, it reads more lines to retry,
+        // so the binary is not sent on stdin then.
+        let password = spec.privilege.as_ref().and_then(Privilege::password);
+        // another user than root cannot read the home directory of the login user
+        let shared = spec.privilege.as_ref().is_some_and(|p| !p.is_root());
+        let cache = spec.cache && !shared;
+
+        let auth_token = spec.auth.then(generate_token);
+
+        if let Err(e) = staging::sweep(self).await {
+            warn!("failed to sweep staged files: {}", e);
+        }
 
-            let channel = self.channel_open_session().await?;
-            channel.exec(true, command).await?;
-            tokio::io::copy(&mut binary, &mut channel.make_writer()).await?;
-            channel.eof().await?;
+        // elfexec reads the binary from stdin, so it cannot be used in stdio mode
+        let has_elfexec = if spec.stdio || cache || password.is_some() {
+            false
+        } else {
+            debug!("which elfexec on remote");
+            self.output(b"which elfexec").await?.success()
+        };
 
-            channel
+        // the cache keeps the binary as a file, so memfd is pointless
+        let memfd_loader = if has_elfexec || cache || password.is_some() {
+            None
         } else {
-            debug!("fall back to write to tmp file (exec only mode)");
-
-            // create tempfile
-            let tmpfile = self.output(b"mktemp").await?;
-            if !tmpfile.code.sucess() {
-                error!("mktemp: {}", String::from_utf8_lossy(&tmpfile.stderr));
-                error!("mktemp: status code={:?}", tmpfile.code);
-                return Err(RpcStartError::LaunchFail(tmpfile.code.code().unwrap_or(1)));
-            }
+            memfd::probe(self).await?
+        };
+
+        let compression = negotiate_compression(self, &spec.compression).await?;
 
-            let tmpfile = tmpfile.stdout;
-            debug!("create tmpfile: {}", String::from_utf8_lossy(&tmpfile));
-            // copy
-            let mut command = b"cat > ".to_vec();
-            command.extend_from_slice(&tmpfile);
-            let channel = self.channel_open_session().await?;
-            channel.exec(true, command).await?;
-            tokio::io::copy(&mut binary, &mut channel.make_writer()).await?;
+        let (channel, launch_method) = if has_elfexec {
+            debug!("elfexec is available. using it");
+            let decompress = compression.map(|c| c.decompress_command());
+            let channel = exec_server(self, spec, b"elfexec", decompress.as_deref()).await?;
+            send_auth_token(&channel, spec, auth_token.as_deref()).await?;
+            tokio::io::copy(
+                &mut encoder(&mut binary, compression),
+                &mut channel.make_writer(),
+            )
+            .await?;
             channel.eof().await?;
 
-            let (_, status) = wait_until_exit("copy", channel).await;
-            if status != Some(0) {
-                return Err(RpcStartError::LaunchFail(status.unwrap_or(1)));
+            (channel, LaunchMethod::Elfexec)
+        } else if let Some(loader) = memfd_loader {
+            debug!("load the binary into memfd with {:?}", loader);
+            let mut buf = vec![];
+            binary.read_to_end(&mut buf).await?;
+            // the decompressor would consume the rest of stdin in stdio mode
+            let compression = compression.filter(|_| !spec.stdio);
+            let decompress = compression.map(|c| c.decompress_command());
+            let program = loader.program(buf.len());
+            let channel = exec_server(self, spec, &program, decompress.as_deref()).await?;
+            send_auth_token(&channel, spec, auth_token.as_deref()).await?;
+            tokio::io::copy(
+                &mut encoder(&buf[..], compression),
+                &mut channel.make_writer(),
+            )
+            .await?;
+            if !spec.stdio {
+                channel.eof().await?;
             }
 
-            // chmod
-            let mut command = b"chmod +x ".to_vec();
-            command.extend_from_slice(&tmpfile);
-            let chmod = self.output(command).await?;
-            if !chmod.code.sucess() {
-                error!("chmod: {}", String::from_utf8_lossy(&chmod.stderr));
-                error!("chmod: status code={:?}", chmod.code);
-                return Err(RpcStartError::LaunchFail(chmod.code.code().unwrap_or(1)));
+            (channel, loader.launch_method())
+        } else {
+            let (program, launch_method) = if cache {
+                let path =
+                    cache::upload_cached(self, &mut binary, spec.cache_max_entries, compression)
+                        .await?;
+                (shell_quote(path.as_bytes()), LaunchMethod::Cache(path))
+            } else {
+                debug!("fall back to write to tmp file");
+                let path =
+                    upload_tmpfile(self, &mut binary, compression, &spec.staging_dirs, shared)
+                        .await?;
+                (shell_quote(path.as_bytes()), LaunchMethod::TmpFile(path))
+            };
+
+            let spec = match launch_method {
+                // the server removes the tmp file itself, see `transport`
+                LaunchMethod::TmpFile(_) => &spec.clone().env(UNLINK_SELF_ENV, "1"),
+                _ => spec,
+            };
+            let channel = exec_server(self, spec, &program, None).await?;
+            send_auth_token(&channel, spec, auth_token.as_deref()).await?;
+            if !spec.stdio {
+                channel.eof().await?;
             }
 
-            // launch cleanup process
-            let mut command = b"bash -c \"cat;rm -f \"".to_vec();
-            command.extend_from_slice(&tmpfile);
-            let trap = self.channel_open_session().await?;
-            trap.exec(true, command).await?;
-            CleanupGuard::new(trap).spawn();
+            (channel, launch_method)
+        };
+        debug!("launched with {:?}", launch_method);
 
-            // exec
-            let mut command = tmpfile;
-            command.extend_from_slice(b" ");
-            command.extend_from_slice(&args.into());
+        let mut session = self
+            .read_handshake_information_timeout(channel, spec.handshake_timeout)
+            .await?;
+        session.auth_token = auth_token;
+        session.launch_method = Some(launch_method);
+        Ok(session)
+    }
 
-            let channel = self.channel_open_session().await?;
-            channel.exec(true, command).await?;
-            channel.eof().await?;
+    async fn remote_target(&self) -> Result<RemoteTarget, Self::Error> {
+        let probe = self.output(sh_c(PROBE_SCRIPT)).await?;
+        let output = String::from_utf8_lossy(&probe.stdout);
+        let target = probe
+            .success()
+            .then(|| RemoteTarget::parse(&output))
+            .flatten()
+            .ok_or_else(|| RpcStartError::RemoteTargetDetectionFail(output.to_string()))?;
+        debug!("remote target: {}", target);
+        Ok(target)
+    }
 
-            channel
+    async fn exec_rpc_server_multiarch<I, K, R>(
+        &self,
+        binaries: I,
+        spec: &LaunchSpec,
+    ) -> Result<SshRpcSession<Channel<Msg>, ChannelStream<Msg>>, Self::Error>
+    where
+        I: IntoIterator<Item = (K, R)>,
+        K: AsRef<str>,
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let target = self.remote_target().await?;
+        let mut binaries: Vec<(String, R)> = binaries
+            .into_iter()
+            .map(|(triple, binary)| (triple.as_ref().to_string(), binary))
+            .collect();
+        let Some(selected) = target
+            .select(binaries.iter().map(|(triple, _)| triple.as_str()))
+            .map(str::to_string)
+        else {
+            return Err(RpcStartError::NoMatchingBinary {
+                target,
+                available: binaries.into_iter().map(|(triple, _)| triple).collect(),
+            });
         };
-
-        self.read_handshake_information(channel).await
+        debug!("selected binary: {}", selected);
+        let index = binaries.iter().position(|(t, _)| *t == selected).unwrap();
+        let (_, binary) = binaries.swap_remove(index);
+        self.exec_rpc_server_with(binary, spec).await
     }
 
-    async fn read_handshake_information(
+    async fn read_handshake_information_timeout(
         &self,
         channel: Channel<Msg>,
+        timeout: std::time::Duration,
     ) -> Result<SshRpcSession<Channel<Msg>, ChannelStream<Msg>>, Self::Error> {
         let mut channel = channel;
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        // stdout of the server, lines before the handshake (e.g. motd) are skipped
+        let mut stdout = vec![];
+        let mut consumed = 0;
+        let mut stderr = vec![];
 
         let handshake_information: HandshakeInformation = loop {
-            let Some(msg) = channel.wait().await else {
+            let Ok(msg) = tokio::time::timeout_at(deadline, channel.wait()).await else {
+                stdout.extend_from_slice(&stderr);
+                return Err(RpcStartError::HandshakeTimeout {
+                    output: String::from_utf8_lossy(&stdout).into_owned(),
+                });
+            };
+            let Some(msg) = msg else {
                 break None;
             };
             match msg {
                 ChannelMsg::Data { ref data } => {
-                    let line = String::from_utf8_lossy(data);
-                    let line = line.split('\n').next().unwrap();
-                    let handshake_information: HandshakeInformation = line.parse()?;
-                    break Some(handshake_information);
+                    stdout.extend_from_slice(data);
+                    if let Some(info) = find_handshake_line(&stdout, &mut consumed)? {
+                        if consumed != stdout.len() {
+                            warn!("data after handshake information is dropped");
+                        }
+                        break Some(info);
+                    }
                 }
                 ChannelMsg::ExtendedData { ref data, ext: 1 } => {
                     let line = String::from_utf8_lossy(data);
                     error!("{}", line);
+                    stderr.extend_from_slice(data);
                 }
                 ChannelMsg::ExitStatus { exit_status } => {
                     return Err(RpcStartError::LaunchFail(exit_status));
                 }
+                ChannelMsg::ExitSignal { .. } => {
+                    if let Some(exit) = crate::client::ExitStatus::from_msg(&msg) {
+                        return Err(RpcStartError::LaunchKilled(exit));
+                    }
+                }
                 _ => {}
             }
         }
         .ok_or(RpcStartError::HandshakeInformationNotReceived)?;
 
-        let addr = handshake_information.network_addr;
-
-        let stream = self
-            .channel_open_direct_tcpip(&addr.ip().to_string(), addr.port() as u32, "localhost", 0)
-            .await?;
+        let stream = match handshake_information.network_addr {
+            NetworkAddr::Stdio => {
+                debug!("stdio mode: use channel of execution as stream");
+                return Ok(SshRpcSession {
+                    handshake_information,
+                    channel: None,
+                    stream: channel.into_stream(),
+                    auth_token: None,
+                    launch_method: None,
+                });
+            }
+            ref addr => open_forward(self, addr).await?,
+        };
 
         Ok(SshRpcSession {
             handshake_information,
-            channel,
+            channel: Some(channel),
             stream: stream.into_stream(),
+            auth_token: None,
+            launch_method: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_handshake_line() {
+        let mut consumed = 0;
+        let buf = b"Welcome to host\n\n1|1|tcp|127.0.0.1:1234|tarp";
+        assert_eq!(find_handshake_line(buf, &mut consumed).unwrap(), None);
+        assert_eq!(consumed, 17);
+
+        let buf = b"Welcome to host\n\n1|1|tcp|127.0.0.1:1234|tarpc<bincode>\r\n";
+        let info = find_handshake_line(buf, &mut consumed).unwrap().unwrap();
+        assert_eq!(info.protcol, crate::Protcol::TarpcBincode);
+        assert_eq!(consumed, buf.len());
+
+        let mut consumed = 0;
+        assert!(find_handshake_line(b"1|1|tcp|\n", &mut consumed).is_err());
+    }
+
+    /// Session of a server listening on `addr`, without the channel of execution.
+    fn session(
+        network_addr: NetworkAddr,
+        auth_token: Option<&str>,
+    ) -> SshRpcSession<(), tokio::io::DuplexStream> {
+        SshRpcSession {
+            handshake_information: HandshakeInformation {
+                core_protcol_version: 1,
+                app_protocol_version: 1,
+                network_type: match network_addr {
+                    NetworkAddr::Stdio => crate::NetworkType::Stdio,
+                    _ => crate::NetworkType::Tcp,
+                },
+                network_addr,
+                protcol: crate::Protcol::TarpcBincode,
+                server_cert: None,
+                pid: None,
+            },
+            channel: None,
+            stream: tokio::io::duplex(1).0,
+            auth_token: auth_token.map(str::to_string),
+            launch_method: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connector() {
+        use crate::transport::{serve_incoming, Incoming, ServeOptions};
+        use tarpc::{context, ServerError};
+
+        let handle = Arc::new(crate::client::test_server::connect().await);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = NetworkAddr::Tcp(listener.local_addr().unwrap());
+        let incoming = Incoming::new(listener, Some("secret".to_string()));
+        let options = ServeOptions::new()
+            .multiple_connections(true)
+            .idle_timeout(std::time::Duration::from_millis(500));
+        let add_one = tarpc::server::serve(|_, x: u32| async move { Ok::<_, ServerError>(x + 1) });
+
+        let connector = Connector::new(handle.clone(), &session(addr.clone(), Some("secret")));
+        let connector = connector.expect("tcp server accepts more connections");
+        assert_eq!(connector.handshake_information().network_addr, addr);
+        let wrong = Connector::new(handle.clone(), &session(addr.clone(), Some("wrong"))).unwrap();
+        let ((), ()) = tokio::join!(serve_incoming(incoming, add_one, &options), async {
+            // every stream is a new connection with the token
+            for x in [1, 2] {
+                let transport = connector.connect().await.unwrap();
+                let client: tarpc::client::Channel<u32, u32> =
+                    tarpc::client::new(Default::default(), transport).spawn();
+                assert_eq!(client.call(context::current(), x).await.unwrap(), x + 1);
+            }
+            let (_, transport) = connector
+                .open_session()
+                .await
+                .unwrap()
+                .try_into_transport(1)
+                .unwrap();
+            let client: tarpc::client::Channel<u32, u32> =
+                tarpc::client::new(Default::default(), transport).spawn();
+            assert_eq!(client.call(context::current(), 41).await.unwrap(), 42);
+
+            let transport = wrong.connect().await.unwrap();
+            let client: tarpc::client::Channel<u32, u32> =
+                tarpc::client::new(Default::default(), transport).spawn();
+            assert!(client.call(context::current(), 1).await.is_err());
+        });
+
+        // the stream of stdio mode cannot be opened again
+        assert!(Connector::new(handle, &session(NetworkAddr::Stdio, None)).is_none());
+    }
+}
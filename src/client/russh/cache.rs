@@ -0,0 +1,208 @@
+//! Content-addressed cache of server binaries on remote (`~/.cache/sshrpc/<sha256>`).
+use super::RpcStartError;
+use crate::client::command::CommandExt;
+use crate::client::compress::{encoder, Compression};
+use crate::client::launch::sh_c;
+use russh::client::{Handle, Handler};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, error};
+
+const CACHE_DIR: &str = "\"$HOME/.cache/sshrpc\"";
+
+/// Upload `binary` to the cache unless it is cached already.
+/// Returns the absolute path of the cached binary, not quoted.
+pub(super) async fn upload_cached<H, R>(
+    handle: &Handle<H>,
+    binary: &mut R,
+    max_entries: usize,
+    compression: Option<Compression>,
+) -> Result<String, RpcStartError>
+where
+    H: Handler,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut buf = vec![];
+    binary.read_to_end(&mut buf).await?;
+    let hash = format!("{:x}", Sha256::digest(&buf));
+
+    let check = handle.output(sh_c(check_script(&hash))).await?;
+    let path = String::from_utf8_lossy(&check.stdout)
+        .trim_end_matches('\n')
+        .to_string();
+    if check.success() {
+        debug!("cache hit: {}", hash);
+        return Ok(path);
+    }
+
+    debug!("cache miss: {}", hash);
+    let channel = handle.channel_open_session().await?;
+    channel
+        .exec(true, sh_c(upload_script(&hash, max_entries, compression)))
+        .await?;
+    // the hash is of the uncompressed binary
+    channel.data(encoder(&buf[..], compression)).await?;
+    channel.eof().await?;
+
+    let (_, status) = super::wait_until_exit("cache", channel).await;
+    if status != Some(0) {
+        error!("cache: failed to upload {}: status code={:?}", hash, status);
+        return Err(RpcStartError::CacheUploadFail(hash));
+    }
+    Ok(path)
+}
+
+/// Print the expanded path of the cached binary, and succeed if it is cached intact.
+/// It is touched to keep recently used entries from eviction.
+fn check_script(hash: &str) -> String {
+    format!(
+        r#"p={dir}/{hash}; printf '%s\n' "$p"; test -x "$p" && [ "$( (sha256sum || shasum -a 256) < "$p" | cut -d' ' -f1)" = {hash} ] && touch "$p""#,
+        dir = CACHE_DIR,
+        hash = hash,
+    )
+}
+
+/// Write the binary from stdin to the cache and evict old entries.
+/// At most `max_entries` entries are kept, but `hash` itself is never evicted.
+fn upload_script(hash: &str, max_entries: usize, compression: Option<Compression>) -> String {
+    format!(
+        r#"set -e
+d={dir}
+mkdir -p "$d"
+chmod 700 "$d"
+t="$d/{hash}.tmp.$$"
+trap 'rm -f "$t"' EXIT
+{decompress} > "$t"
+h=$( (sha256sum || shasum -a 256) < "$t" | cut -d' ' -f1)
+if [ "$h" != {hash} ]; then
+  echo "hash mismatch: $h" >&2
+  exit 1
+fi
+chmod 700 "$t"
+mv -f "$t" "$d/{hash}"
+trap - EXIT
+cd "$d"
+ls -t | grep -v '\.tmp\.' | grep -vx {hash} | tail -n +{keep} | xargs rm -f"#,
+        dir = CACHE_DIR,
+        hash = hash,
+        // the entry of `hash` is one of `max_entries`
+        keep = max_entries.max(1),
+        decompress = compression.map_or("cat".to_string(), |c| c.decompress_command()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Output, Stdio};
+    use std::time::{Duration, SystemTime};
+
+    /// Run `script` with `home` as `$HOME`.
+    fn run(home: &Path, script: &str, stdin: &[u8]) -> Output {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .env("HOME", home)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    fn home(name: &str) -> PathBuf {
+        let home =
+            std::env::temp_dir().join(format!("sshrpc-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(home.join(".cache/sshrpc")).unwrap();
+        home
+    }
+
+    /// Add an entry used `age` seconds ago.
+    fn add_entry(home: &Path, name: &str, age: u64) {
+        let file = std::fs::File::create(home.join(".cache/sshrpc").join(name)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn entries(home: &Path) -> Vec<String> {
+        let mut entries = std::fs::read_dir(home.join(".cache/sshrpc"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    fn hash(binary: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(binary))
+    }
+
+    #[test]
+    fn test_upload_evicts_old_entries() {
+        let home = home("evict");
+        let binary = b"binary";
+        let hash = hash(binary);
+        add_entry(&home, "old", 300);
+        add_entry(&home, "older", 400);
+        add_entry(&home, "new", 100);
+
+        let output = run(&home, &upload_script(&hash, 2, None), binary);
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(entries(&home), vec![hash.clone(), "new".to_string()]);
+        assert_eq!(
+            std::fs::read(home.join(".cache/sshrpc").join(&hash)).unwrap(),
+            binary
+        );
+
+        // The uploaded entry is kept even if no entry is allowed.
+        add_entry(&home, "newest", 0);
+        let output = run(&home, &upload_script(&hash, 0, None), binary);
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(entries(&home), vec![hash.clone()]);
+
+        // A corrupted binary is not cached.
+        let output = run(&home, &upload_script(&hash, 2, None), b"corrupted");
+        assert!(!output.status.success());
+        assert_eq!(entries(&home), vec![hash]);
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let home = home("check");
+        let binary = b"binary";
+        let hash = hash(binary);
+        let path = home.join(".cache/sshrpc").join(&hash);
+
+        // miss
+        let output = run(&home, &check_script(&hash), b"");
+        assert!(!output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim_end(),
+            path.to_string_lossy()
+        );
+
+        // hit
+        let output = run(&home, &upload_script(&hash, 2, None), binary);
+        assert!(output.status.success(), "{:?}", output);
+        let output = run(&home, &check_script(&hash), b"");
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim_end(),
+            path.to_string_lossy()
+        );
+
+        // An entry modified after upload is a miss, so it is uploaded again.
+        std::fs::write(&path, b"modified").unwrap();
+        let output = run(&home, &check_script(&hash), b"");
+        assert!(!output.status.success());
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! `CommandExt` on a russh connection.
+use crate::client::command::{Child, ChildOutput, Command, CommandError, CommandExt, Output};
+use crate::client::ExitStatus;
+use russh::client::{Handle, Handler};
+use russh::ChannelMsg;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+impl<H> CommandExt for Handle<H>
+where
+    H: Handler,
+{
+    type Error = CommandError;
+
+    async fn run(&self, command: &Command) -> Result<Output, Self::Error> {
+        self.spawn(command).await?.output().await
+    }
+
+    async fn spawn(&self, command: &Command) -> Result<Child, Self::Error> {
+        // the timeout covers opening the channel and writing stdin too
+        let start = Instant::now();
+        let deadline = command.timeout.map(|timeout| (start + timeout, timeout));
+        let mut channel = until(deadline, self.channel_open_session()).await?;
+        let mut stdin = Box::pin(channel.make_writer());
+        let started = until(deadline, async {
+            channel.exec(true, command.command.clone()).await?;
+            if let Some(data) = &command.stdin {
+                stdin.write_all(data).await?;
+            }
+            Ok::<_, CommandError>(())
+        })
+        .await;
+        if let Err(e) = started {
+            let _ = channel.close().await;
+            return Err(e);
+        }
+
+        let (stdout_tx, stdout) = mpsc::unbounded_channel();
+        let (stderr_tx, stderr) = mpsc::unbounded_channel();
+        let (status_tx, status) = oneshot::channel();
+        let (close, mut close_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut status = None;
+            loop {
+                let msg = tokio::select! {
+                    msg = channel.wait() => msg,
+                    Some(()) = close_rx.recv() => {
+                        let _ = channel.close().await;
+                        break;
+                    }
+                };
+                let Some(msg) = msg else {
+                    break;
+                };
+                match msg {
+                    ChannelMsg::Data { ref data } => {
+                        let _ = stdout_tx.send(data.to_vec());
+                    }
+                    ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                        let _ = stderr_tx.send(data.to_vec());
+                    }
+                    ref msg => {
+                        if let Some(exit) = ExitStatus::from_msg(msg) {
+                            status = Some(exit);
+                        }
+                    }
+                }
+            }
+            let _ = status_tx.send(status);
+        });
+
+        Ok(Child::new(
+            stdin,
+            ChildOutput::new(stdout),
+            ChildOutput::new(stderr),
+            status,
+            close,
+            command.timeout,
+        )
+        .started_at(start))
+    }
+}
+
+/// Await `future` until `deadline`, the instant and the timeout it was made of.
+async fn until<F, T, E>(deadline: Option<(Instant, Duration)>, future: F) -> Result<T, CommandError>
+where
+    F: Future<Output = Result<T, E>>,
+    CommandError: From<E>,
+{
+    let Some((deadline, timeout)) = deadline else {
+        return Ok(future.await?);
+    };
+    match tokio::time::timeout_at(deadline, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(CommandError::Timeout(timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_server;
+
+    #[tokio::test]
+    async fn test_spawn_timeout_writing_stdin() {
+        let handle = test_server::connect().await;
+        // stdin is never read, so writing it blocks once the buffers are full
+        let command = Command::new("sleep 10")
+            .stdin(vec![0; 16 << 20])
+            .timeout(Duration::from_millis(300));
+        let spawned = tokio::time::timeout(Duration::from_secs(5), handle.spawn(&command))
+            .await
+            .expect("spawn returns at the timeout");
+        assert!(matches!(spawned, Err(CommandError::Timeout(_))));
+    }
+}
@@ -0,0 +1,151 @@
+//! Fileless execution with `memfd_create(2)` through perl or python3 on remote.
+//!
+//! The loader reads exactly `len` bytes of the binary from stdin into a memfd and execs it,
+//! so the rest of stdin is left to the server (this works in stdio mode too).
+use super::RpcStartError;
+use crate::client::command::CommandExt;
+use crate::client::launch::{sh_c, shell_quote, LaunchMethod};
+use russh::client::{Handle, Handler};
+use tracing::debug;
+
+// perl has no memfd_create, so call the syscall by number
+const PERL: &str = r#"use strict;use Config;
+my %nr=(x86_64=>319,aarch64=>279,riscv64=>279,arm=>385,i386=>356,i686=>356,powerpc64=>360,powerpc64le=>360,ppc64=>360,ppc64le=>360,s390x=>350);
+my ($arch)=$Config{archname}=~/^([^-]+)/;
+my $nr=$nr{$arch} or die "memfd_create: unknown arch $arch\n";
+my $name="sshrpc";
+my $fd=syscall($nr,$name,0);
+die "memfd_create: $!\n" if $fd<0;
+my $n=shift;
+exit 0 if $n eq "-";
+open(my $f,">&=",$fd) or die "open: $!\n";
+while($n>0){
+  my $r=sysread(STDIN,my $b,$n<65536?$n:65536);
+  die "read: unexpected eof\n" unless $r;
+  syswrite($f,$b)==$r or die "write: $!\n";
+  $n-=$r;
+}
+my $p="/proc/self/fd/$fd";
+exec {$p} $p,@ARGV or die "exec: $!\n";"#;
+
+// memfd_create of python defaults to MFD_CLOEXEC, so pass 0 explicitly
+const PYTHON3: &str = r#"import os,sys
+fd=os.memfd_create("sshrpc",0)
+if sys.argv[1]=="-":
+    sys.exit(0)
+n=int(sys.argv[1])
+while n>0:
+    b=os.read(0,min(n,65536))
+    if not b:
+        sys.exit("read: unexpected eof")
+    while b:
+        w=os.write(fd,b)
+        n-=w
+        b=b[w:]
+p="/proc/self/fd/%d"%fd
+os.execv(p,[p]+sys.argv[2:])"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MemfdLoader {
+    Perl,
+    Python3,
+}
+
+impl MemfdLoader {
+    /// Command line of the loader, without the arguments for the binary.
+    /// `len` is `None` to only check memfd_create works.
+    fn command(&self, len: Option<usize>) -> Vec<u8> {
+        let (interpreter, script) = match self {
+            MemfdLoader::Perl => ("perl -e ", PERL),
+            MemfdLoader::Python3 => ("python3 -c ", PYTHON3),
+        };
+        let mut command = interpreter.as_bytes().to_vec();
+        command.extend_from_slice(&shell_quote(script.as_bytes()));
+        command.push(b' ');
+        command.extend_from_slice(
+            len.map_or("-".to_string(), |len| len.to_string())
+                .as_bytes(),
+        );
+        command
+    }
+
+    /// Program to launch the binary of `len` bytes from stdin.
+    pub(super) fn program(&self, len: usize) -> Vec<u8> {
+        self.command(Some(len))
+    }
+
+    pub(super) fn launch_method(&self) -> LaunchMethod {
+        match self {
+            MemfdLoader::Perl => LaunchMethod::MemfdPerl,
+            MemfdLoader::Python3 => LaunchMethod::MemfdPython3,
+        }
+    }
+}
+
+/// Find a loader which can create a memfd on remote.
+pub(super) async fn probe<H: Handler>(
+    handle: &Handle<H>,
+) -> Result<Option<MemfdLoader>, RpcStartError> {
+    for loader in [MemfdLoader::Perl, MemfdLoader::Python3] {
+        let mut script = loader.command(None);
+        script.extend_from_slice(b" 2>/dev/null");
+        if handle.output(sh_c(script)).await?.success() {
+            debug!("memfd is available with {:?}", loader);
+            return Ok(Some(loader));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perl_syscall_numbers() {
+        let command = String::from_utf8(MemfdLoader::Perl.program(10)).unwrap();
+        // `$Config{archname}` of each arch and the number of memfd_create
+        for (archname, nr) in [
+            ("x86_64-linux-gnu-thread-multi", 319),
+            ("aarch64-linux-gnu-thread-multi", 279),
+            ("riscv64-linux-gnu-thread-multi", 279),
+            ("arm-linux-gnueabihf-thread-multi-64int", 385),
+            ("i386-linux-thread-multi", 356),
+            ("i686-linux-gnu-thread-multi-64int", 356),
+            ("powerpc64-linux-gnu-thread-multi", 360),
+            ("powerpc64le-linux-gnu-thread-multi", 360),
+            ("ppc64le-linux-thread-multi", 360),
+            ("s390x-linux-gnu-thread-multi", 350),
+        ] {
+            let arch = archname.split('-').next().unwrap();
+            assert!(
+                command.contains(&format!("{}=>{},", arch, nr))
+                    || command.contains(&format!("{}=>{});", arch, nr)),
+                "{}",
+                archname
+            );
+        }
+        assert!(command.ends_with(" 10"));
+    }
+
+    /// Check memfd_create works on this host, like `probe` does on remote.
+    #[test]
+    fn test_probe_command() {
+        for loader in [MemfdLoader::Perl, MemfdLoader::Python3] {
+            let command = String::from_utf8(loader.command(None)).unwrap();
+            assert!(command.ends_with(" -"));
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .status()
+                .unwrap();
+            // 127 if the interpreter is not installed
+            assert!(
+                status.success() || status.code() == Some(127),
+                "{:?}: {:?}",
+                loader,
+                status
+            );
+        }
+    }
+}
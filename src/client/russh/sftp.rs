@@ -0,0 +1,165 @@
+//! Upload of the binary over the SFTP subsystem, which needs no shell to write the binary.
+//! The tmp file is still made by the staging script over the shell, because only an
+//! executed file can tell whether the directory is mounted with `noexec`.
+use super::RpcStartError;
+use russh::client::{Handle, Handler};
+use russh::ChannelMsg;
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::RawSftpSession;
+use russh_sftp::protocol::{FileAttributes, OpenFlags, Packet, StatusCode};
+use tokio::io::AsyncReadExt;
+use tracing::debug;
+
+/// Extension of OpenSSH to rename over an existing file atomically, like `rename(2)`
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+
+/// Extension of OpenSSH to flush a file to the disk
+const FSYNC: &str = "fsync@openssh.com";
+
+/// Bytes sent by one write request, which any server accepts
+const WRITE_LEN: usize = 32 * 1024;
+
+/// Data of the `posix-rename@openssh.com` request: the old and the new path as SSH strings.
+fn posix_rename_data(oldpath: &str, newpath: &str) -> Vec<u8> {
+    let mut data = vec![];
+    for path in [oldpath, newpath] {
+        data.extend_from_slice(&(path.len() as u32).to_be_bytes());
+        data.extend_from_slice(path.as_bytes());
+    }
+    data
+}
+
+/// SFTP session with the part file opened, ready to receive the binary.
+pub(super) struct SftpUpload {
+    sftp: RawSftpSession,
+    /// handle of the opened part file
+    file: String,
+    part: String,
+    path: String,
+    fsync: bool,
+    posix_rename: bool,
+}
+
+impl SftpUpload {
+    /// Open `<path>.part` exclusively with the final mode, to be renamed to `path` later.
+    /// Returns `None` if SFTP is not available, so the caller can fall back to the shell.
+    /// Nothing is read from the binary yet then.
+    pub(super) async fn open<H: Handler>(
+        handle: &Handle<H>,
+        path: &str,
+        shared: bool,
+    ) -> Option<Self> {
+        let mut channel = handle.channel_open_session().await.ok()?;
+        channel.request_subsystem(true, "sftp").await.ok()?;
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => (),
+            msg => {
+                debug!("sftp: subsystem is not available: {:?}", msg);
+                return None;
+            }
+        }
+        let sftp = RawSftpSession::new(channel.into_stream());
+        let version = match sftp.init().await {
+            Ok(version) => version,
+            Err(e) => {
+                debug!("sftp: failed to start: {}", e);
+                return None;
+            }
+        };
+        let has_extension = |name: &str| version.extensions.get(name).is_some_and(|e| e == "1");
+        let (fsync, posix_rename) = (has_extension(FSYNC), has_extension(POSIX_RENAME));
+        let part = format!("{}.part", path);
+        let attributes = FileAttributes {
+            // readable by everyone when another user runs it
+            permissions: Some(if shared { 0o755 } else { 0o700 }),
+            ..FileAttributes::empty()
+        };
+        let flags = OpenFlags::CREATE | OpenFlags::EXCLUDE | OpenFlags::WRITE;
+        match sftp.open(part.clone(), flags, attributes).await {
+            Ok(file) => Some(Self {
+                sftp,
+                file: file.handle,
+                part,
+                path: path.to_string(),
+                fsync,
+                posix_rename,
+            }),
+            Err(e) => {
+                debug!("sftp: failed to create {}: {}", part, e);
+                None
+            }
+        }
+    }
+
+    /// Write `binary`, fsync and rename it over the placeholder made by the staging script.
+    pub(super) async fn write<R>(self, binary: &mut R) -> Result<(), RpcStartError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let result = self.write_and_rename(binary).await;
+        if result.is_err() {
+            // fails if the file is closed already
+            let _ = self.sftp.close(self.file.as_str()).await;
+            let _ = self.sftp.remove(self.part.as_str()).await;
+        }
+        let _ = self.sftp.close_session();
+        result
+    }
+
+    async fn write_and_rename<R>(&self, binary: &mut R) -> Result<(), RpcStartError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut buf = vec![0; WRITE_LEN];
+        let mut offset = 0;
+        loop {
+            let n = binary.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            self.sftp
+                .write(self.file.as_str(), offset, buf[..n].to_vec())
+                .await?;
+            offset += n as u64;
+        }
+        if self.fsync {
+            self.sftp.fsync(self.file.as_str()).await?;
+        }
+        self.sftp.close(self.file.as_str()).await?;
+        self.rename().await?;
+        debug!("sftp: uploaded {}", self.path);
+        Ok(())
+    }
+
+    /// Rename the part file to `path`.
+    async fn rename(&self) -> Result<(), SftpError> {
+        if !self.posix_rename {
+            // SFTP v3 rename does not replace an existing file, so the placeholder is removed
+            // first. The path is missing for a moment, but nobody else uses it.
+            self.sftp.remove(self.path.as_str()).await?;
+            self.sftp
+                .rename(self.part.as_str(), self.path.as_str())
+                .await?;
+            return Ok(());
+        }
+        let data = posix_rename_data(&self.part, &self.path);
+        match self.sftp.extended(POSIX_RENAME, data).await? {
+            Packet::Status(status) if status.status_code == StatusCode::Ok => Ok(()),
+            Packet::Status(status) => Err(status.into()),
+            _ => Err(SftpError::UnexpectedPacket),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posix_rename_data() {
+        assert_eq!(
+            posix_rename_data("/tmp/a.part", "/tmp/a"),
+            b"\0\0\0\x0b/tmp/a.part\0\0\0\x06/tmp/a".to_vec()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Choice of the remote directory to write the binary to.
+use super::RpcStartError;
+use crate::client::command::{CommandError, CommandExt};
+use crate::client::launch::{sh_c, shell_quote};
+use russh::client::{Handle, Handler};
+use tracing::{debug, warn};
+
+/// Candidates when `LaunchSpec::staging_dir` is not given.
+/// `/tmp` is often mounted with `noexec`, so it is the last resort.
+const DEFAULT_STAGING_DIRS: [&[u8]; 3] = [b"/dev/shm", b"\"$HOME\"", b"\"${TMPDIR:-/tmp}\""];
+
+/// Candidates when the server runs as another user, who cannot enter private directories.
+const SHARED_STAGING_DIRS: [&[u8]; 2] = [b"/dev/shm", b"/tmp"];
+
+/// Markers of the staged files, named after the file and containing its path.
+/// Files of crashed runs are found by these and removed on the next launch.
+const MARKER_DIR: &str = "\"$HOME/.cache/sshrpc-staged\"";
+
+/// Staged files older than this (in minutes) are removed by the sweep.
+/// Running servers are not affected, they only keep a launch in progress from being broken.
+const STALE_MINUTES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum StagingFailReason {
+    /// `mktemp` failed, e.g. the directory does not exist
+    Mktemp,
+    /// the directory is not writable or full
+    NotWritable,
+    /// files in the directory cannot be executed (`noexec` mount)
+    Noexec,
+}
+
+/// A staging directory which cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{dir}: {reason}")]
+pub struct StagingDirError {
+    pub dir: String,
+    pub reason: StagingFailReason,
+}
+
+/// Script to remove the staged files left by earlier runs, which are older than `STALE_MINUTES`.
+fn sweep_script() -> String {
+    format!(
+        r#"m={marker_dir}
+[ -d "$m" ] || exit 0
+find "$m" -type f -mmin +{stale} 2>/dev/null | while IFS= read -r f; do
+  IFS= read -r p < "$f" && rm -f "$p" "$p.part"
+  rm -f "$f"
+done
+"#,
+        marker_dir = MARKER_DIR,
+        stale = STALE_MINUTES,
+    )
+}
+
+/// Remove the staged files left by earlier runs, e.g. when the client crashed before
+/// `remove_tmpfile`. This runs on every launch, whatever the launch method is.
+/// Failures are only logged, they do not keep the server from starting.
+pub(super) async fn sweep<H: Handler>(handle: &Handle<H>) -> Result<(), CommandError> {
+    let output = handle.output(sh_c(sweep_script())).await?;
+    if !output.success() {
+        warn!(
+            "failed to sweep staged files: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Script to create a tmp file in the first usable directory of `dirs`.
+/// Each failed candidate prints `fail<TAB>reason<TAB>dir`, and the usable one `ok<TAB>path`.
+/// Its marker is written to `MARKER_DIR`, for `sweep` to find it.
+fn script(dirs: &[&[u8]]) -> Vec<u8> {
+    let mut script = format!("m={}\n", MARKER_DIR).into_bytes();
+    for dir in dirs {
+        script.extend_from_slice(b"d=");
+        script.extend_from_slice(dir);
+        script.extend_from_slice(
+            br#"
+if ! t=$(mktemp "$d/.sshrpc.XXXXXX" 2>/dev/null); then
+  printf 'fail\tmktemp\t%s\n' "$d"
+elif ! { printf '#!/bin/sh\nexit 0\n' > "$t" && chmod 700 "$t"; } 2>/dev/null; then
+  rm -f "$t"
+  printf 'fail\tnot_writable\t%s\n' "$d"
+elif ! "$t" 2>/dev/null; then
+  rm -f "$t"
+  printf 'fail\tnoexec\t%s\n' "$d"
+else
+  mkdir -p "$m" && printf '%s\n' "$t" > "$m/${t##*/}"
+  printf 'ok\t%s\n' "$t"
+  exit 0
+fi
+"#,
+        );
+    }
+    script.extend_from_slice(b"exit 1");
+    script
+}
+
+/// Parse the output of `script` into the path of the tmp file or the failures.
+fn parse(output: &str) -> Result<String, Vec<StagingDirError>> {
+    let mut errors = vec![];
+    for line in output.lines() {
+        let mut fields = line.splitn(3, '\t');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("ok"), Some(path), None) => return Ok(path.to_string()),
+            (Some("fail"), Some(reason), Some(dir)) => {
+                if let Ok(reason) = reason.parse() {
+                    errors.push(StagingDirError {
+                        dir: dir.to_string(),
+                        reason,
+                    });
+                }
+            }
+            _ => debug!("staging: ignore {:?}", line),
+        }
+    }
+    Err(errors)
+}
+
+/// Create an executable tmp file in the first usable directory of `dirs` (or the defaults).
+/// `shared` chooses the defaults accessible to other users.
+/// Returns the absolute path, not quoted.
+pub(super) async fn create_tmpfile<H: Handler>(
+    handle: &Handle<H>,
+    dirs: &[Vec<u8>],
+    shared: bool,
+) -> Result<String, RpcStartError> {
+    let dirs: Vec<Vec<u8>> = dirs.iter().map(|dir| shell_quote(dir)).collect();
+    let dirs: Vec<&[u8]> = if dirs.is_empty() && shared {
+        SHARED_STAGING_DIRS.to_vec()
+    } else if dirs.is_empty() {
+        DEFAULT_STAGING_DIRS.to_vec()
+    } else {
+        dirs.iter().map(Vec::as_slice).collect()
+    };
+    let output = handle.output(sh_c(script(&dirs))).await?;
+    match parse(&String::from_utf8_lossy(&output.stdout)) {
+        Ok(path) => {
+            debug!("create tmpfile: {}", path);
+            Ok(path)
+        }
+        Err(errors) => {
+            for e in &errors {
+                warn!("staging dir unavailable: {}", e);
+            }
+            Err(RpcStartError::StagingDirUnavailable(errors))
+        }
+    }
+}
+
+/// Remove the tmp file at `path` and its marker.
+pub(crate) async fn remove_tmpfile<H: Handler>(
+    handle: &Handle<H>,
+    path: &str,
+) -> Result<(), CommandError> {
+    let mut command = b"p=".to_vec();
+    command.extend_from_slice(&shell_quote(path.as_bytes()));
+    command.extend_from_slice(
+        format!("; rm -f \"$p\" \"$p.part\" {}/\"${{p##*/}}\"", MARKER_DIR).as_bytes(),
+    );
+    let output = handle.output(sh_c(command)).await?;
+    if !output.success() {
+        warn!(
+            "failed to remove {}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_server;
+    use std::path::PathBuf;
+
+    /// Empty directory for a test, which is also `$HOME` of the scripts.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sshrpc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".cache/sshrpc-staged")).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_remove_tmpfile() {
+        let handle = test_server::connect().await;
+        let dir = test_dir("remove");
+        let path = dir.join(".sshrpc.abc");
+        std::fs::write(&path, b"").unwrap();
+        std::fs::write(dir.join(".sshrpc.abc.part"), b"").unwrap();
+        remove_tmpfile(&handle, &path.display().to_string())
+            .await
+            .unwrap();
+        assert!(!path.exists());
+        assert!(!dir.join(".sshrpc.abc.part").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sweep_script() {
+        let handle = test_server::connect().await;
+        let dir = test_dir("sweep");
+        let markers = dir.join(".cache/sshrpc-staged");
+        for name in ["old", "new"] {
+            let path = dir.join(name);
+            std::fs::write(&path, b"").unwrap();
+            std::fs::write(markers.join(name), format!("{}\n", path.display())).unwrap();
+        }
+        let touch = format!("touch -t 200001010000 {}", markers.join("old").display());
+        assert!(handle.output(touch).await.unwrap().success());
+
+        let mut command = format!("HOME={} ", dir.display()).into_bytes();
+        command.extend(sh_c(sweep_script()));
+        assert!(handle.output(command).await.unwrap().success());
+        assert!(!dir.join("old").exists());
+        assert!(!markers.join("old").exists());
+        // files of launches in progress are kept
+        assert!(dir.join("new").exists());
+        assert!(markers.join("new").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        let output = "fail\tnoexec\t/tmp\nfail\tmktemp\t/no such\nok\t/dev/shm/.sshrpc.abc\n";
+        assert_eq!(parse(output), Ok("/dev/shm/.sshrpc.abc".to_string()));
+        assert_eq!(
+            parse("fail\tnoexec\t/tmp\nfail\tmktemp\t/no such\n"),
+            Err(vec![
+                StagingDirError {
+                    dir: "/tmp".to_string(),
+                    reason: StagingFailReason::Noexec,
+                },
+                StagingDirError {
+                    dir: "/no such".to_string(),
+                    reason: StagingFailReason::Mktemp,
+                },
+            ])
+        );
+    }
+}
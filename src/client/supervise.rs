@@ -0,0 +1,539 @@
+//! Supervised session, which reconnects and relaunches the server when the connection drops.
+//!
+//! ```ignore
+//! let session = Supervisor::new(connect, binary)
+//!     .launch_spec(LaunchSpec::new().arg("--verbose"))
+//!     .on_event(|event| eprintln!("{:?}", event))
+//!     .spawn(|transport| WorldClient::new(Default::default(), transport).spawn())
+//!     .await?;
+//! // the client is swapped after reconnection, so get it for each call
+//! session.client().hello(context::current(), "world".into()).await?;
+//! // or share a handle, which does the same
+//! let handle = session.handle();
+//! tokio::spawn(async move { handle.client().hello(context::current(), "again".into()).await });
+//! ```
+use crate::client::{LaunchSpec, ServerExit, SshRpcExt, SshRpcSession};
+use crate::remote_log::ServerLog;
+use crate::transport::BincodeTransport;
+use crate::AppProtocolVersions;
+use futures_util::future::BoxFuture;
+use russh::client::{Handle, Handler, Msg};
+use russh::ChannelStream;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{oneshot, watch};
+use tracing::{debug, warn};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Interval to check whether the SSH connection is closed.
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time given to the old server to exit before it is killed,
+/// when only the stream is closed and the server is relaunched on the same connection.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delays between reconnection attempts.
+/// The first attempt is made immediately, then the delay doubles from `initial` up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Give up after this many attempts in a row (default: never).
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the `attempt`-th attempt (1-origin).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(attempt - 2).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Events reported to `Supervisor::on_event`.
+#[derive(Debug, Clone)]
+pub enum SupervisorEvent {
+    /// The server or the SSH connection is gone.
+    Disconnected {
+        connection_closed: bool,
+    },
+    /// Going to reconnect after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// A new client is in place.
+    Reconnected {
+        attempt: u32,
+    },
+    ReconnectFailed {
+        attempt: u32,
+        error: String,
+    },
+    /// `Backoff::max_attempts` is reached. The client is not replaced anymore.
+    GaveUp,
+}
+
+type ConnectFn<H> = Arc<dyn Fn() -> BoxFuture<'static, Result<Handle<H>, BoxError>> + Send + Sync>;
+type LaunchFn<H, T> = Arc<
+    dyn for<'a> Fn(&'a Handle<H>) -> BoxFuture<'a, Result<Launched<T>, BoxError>> + Send + Sync,
+>;
+type EventFn = Arc<dyn Fn(&SupervisorEvent) + Send + Sync>;
+
+/// A launched server and its client.
+struct Launched<T> {
+    /// `None` in stdio mode, see `SshRpcSession::server_exit`
+    exit: Option<ServerExit>,
+    /// fired when the stream of the transport ends
+    closed: oneshot::Receiver<()>,
+    client: T,
+}
+
+/// Stream of the transport given to the `make_client` of `Supervisor::spawn`.
+/// It tells the supervisor when the server closes it, e.g. on crash in stdio mode,
+/// where the channel of execution is the stream itself.
+pub struct WatchedStream<S> {
+    inner: S,
+    closed: Option<oneshot::Sender<()>>,
+}
+
+impl<S> WatchedStream<S> {
+    fn new(inner: S) -> (Self, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let stream = Self {
+            inner,
+            closed: Some(tx),
+        };
+        (stream, rx)
+    }
+
+    fn notify<T>(&mut self, closed: bool, poll: Poll<T>) -> Poll<T> {
+        if closed {
+            if let Some(tx) = self.closed.take() {
+                let _ = tx.send(());
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WatchedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let closed = match &poll {
+            Poll::Ready(Ok(())) => buf.remaining() > 0 && buf.filled().len() == filled,
+            Poll::Ready(Err(_)) => true,
+            Poll::Pending => false,
+        };
+        this.notify(closed, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WatchedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.notify(matches!(poll, Poll::Ready(Err(_))), poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.notify(matches!(poll, Poll::Ready(Err(_))), poll)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Builder of `Supervised`.
+pub struct Supervisor<H: Handler> {
+    connect: ConnectFn<H>,
+    binary: Arc<[u8]>,
+    spec: LaunchSpec,
+    app_protocol_versions: AppProtocolVersions,
+    backoff: Backoff,
+    on_event: EventFn,
+    host: String,
+}
+
+impl<H> Supervisor<H>
+where
+    H: Handler + 'static,
+{
+    /// `connect` makes a new authenticated SSH connection, and `binary` is the server to launch.
+    pub fn new<F, Fut, E, B>(connect: F, binary: B) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Handle<H>, E>> + Send + 'static,
+        E: Into<BoxError>,
+        B: Into<Arc<[u8]>>,
+    {
+        let connect = Arc::new(connect);
+        Self {
+            connect: Arc::new(move || {
+                let connect = connect.clone();
+                Box::pin(async move { connect().await.map_err(Into::into) })
+            }),
+            binary: binary.into(),
+            spec: LaunchSpec::new(),
+            app_protocol_versions: 1.into(),
+            backoff: Backoff::default(),
+            on_event: Arc::new(|_| ()),
+            host: String::new(),
+        }
+    }
+
+    /// How to launch the server, on every (re)connection.
+    pub fn launch_spec(mut self, spec: LaunchSpec) -> Self {
+        self.spec = spec;
+        self
+    }
+
+    /// App protocol versions advertised to the server (see `LaunchSpec::app_protocol_versions`)
+    /// and given to `SshRpcSession::try_into_transport` (default: 1).
+    pub fn app_protocol_versions<V: Into<AppProtocolVersions>>(mut self, versions: V) -> Self {
+        self.app_protocol_versions = versions.into();
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Host name to tag the forwarded server logs with (see `remote_log`).
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Called on disconnection and on each reconnection attempt.
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: Fn(&SupervisorEvent) + Send + Sync + 'static,
+    {
+        self.on_event = Arc::new(on_event);
+        self
+    }
+
+    /// Connect, launch the server and make a client with `make_client`, then keep it alive.
+    /// The first connection is made before returning, and its error is returned as is.
+    pub async fn spawn<M, T, Item, SinkItem>(
+        self,
+        make_client: M,
+    ) -> Result<Supervised<T>, BoxError>
+    where
+        M: Fn(BincodeTransport<WatchedStream<ChannelStream<Msg>>, Item, SinkItem>) -> T
+            + Send
+            + Sync
+            + 'static,
+        T: Clone + Send + Sync + 'static,
+        Item: for<'de> Deserialize<'de> + Send + 'static,
+        SinkItem: Serialize + Send + 'static,
+    {
+        let binary = self.binary;
+        let versions = self.app_protocol_versions;
+        let spec = self.spec.app_protocol_versions(versions.clone());
+        let make_client = Arc::new(make_client);
+        let host = self.host;
+        let launch: LaunchFn<H, T> = Arc::new(move |handle| {
+            let binary = binary.clone();
+            let spec = spec.clone();
+            let versions = versions.clone();
+            let make_client = make_client.clone();
+            let host = host.clone();
+            Box::pin(async move {
+                let session = handle.exec_rpc_server_with(&binary[..], &spec).await?;
+                let pid = session.handshake_information.pid;
+                let launch_method = session.launch_method.clone();
+                let (stream, closed) = WatchedStream::new(session.stream);
+                let session = SshRpcSession {
+                    handshake_information: session.handshake_information,
+                    channel: session.channel,
+                    stream,
+                    auth_token: session.auth_token,
+                    launch_method: session.launch_method,
+                };
+                let (channel, transport) =
+                    session.try_into_transport(versions).map_err(|e| e.error)?;
+                let exit = channel.map(|channel| {
+                    ServerExit::with_log(channel, pid, ServerLog::new(&host))
+                        .launch_method(launch_method.as_ref())
+                });
+                Ok(Launched {
+                    exit,
+                    closed,
+                    client: make_client(transport),
+                })
+            })
+        });
+
+        let handle = (self.connect)().await?;
+        let launched = launch(&handle).await?;
+        let (tx, rx) = watch::channel(launched.client);
+        let task = tokio::spawn(supervise(
+            self.connect,
+            launch,
+            self.backoff,
+            self.on_event,
+            tx,
+            handle,
+            launched.exit,
+            launched.closed,
+        ));
+        Ok(Supervised { rx, task })
+    }
+}
+
+/// A client kept connected by `Supervisor`.
+/// Dropping this stops the supervision and closes the connection.
+pub struct Supervised<T> {
+    rx: watch::Receiver<T>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl<T: Clone> Supervised<T> {
+    /// The current client.
+    /// It is replaced after reconnection and the kept one fails forever,
+    /// so get it for each call, or keep a `handle` instead.
+    pub fn client(&self) -> T {
+        self.rx.borrow().clone()
+    }
+
+    /// A handle to get the current client, which can be cloned and sent to other tasks.
+    /// It does not keep the supervision alive.
+    pub fn handle(&self) -> SupervisedHandle<T> {
+        SupervisedHandle {
+            rx: self.rx.clone(),
+        }
+    }
+
+    /// Wait until the client is replaced.
+    /// Returns false when the supervision is over (see `SupervisorEvent::GaveUp`).
+    pub async fn changed(&mut self) -> bool {
+        self.rx.changed().await.is_ok()
+    }
+}
+
+impl<T> Drop for Supervised<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Handle of `Supervised`, which always gives the current client.
+#[derive(Clone)]
+pub struct SupervisedHandle<T> {
+    rx: watch::Receiver<T>,
+}
+
+impl<T: Clone> SupervisedHandle<T> {
+    /// The current client, see `Supervised::client`.
+    pub fn client(&self) -> T {
+        self.rx.borrow().clone()
+    }
+
+    /// Wait until the client is replaced, see `Supervised::changed`.
+    pub async fn changed(&mut self) -> bool {
+        self.rx.changed().await.is_ok()
+    }
+}
+
+/// Wait until the channel of execution is closed. Never returns if there is no channel.
+async fn server_exited(exit: Option<&ServerExit>) {
+    match exit {
+        Some(exit) => {
+            exit.wait().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until the stream of the transport ends.
+/// Never returns if the stream is dropped without ending (the client is dropped).
+async fn stream_closed(closed: &mut Option<oneshot::Receiver<()>>) {
+    if let Some(rx) = closed.as_mut() {
+        if rx.await.is_ok() {
+            return;
+        }
+        *closed = None;
+    }
+    std::future::pending().await
+}
+
+/// Wait until the server exits or the connection is closed.
+/// Returns whether the connection is closed.
+async fn wait_disconnect<H: Handler>(
+    handle: &Handle<H>,
+    exit: Option<&ServerExit>,
+    closed: oneshot::Receiver<()>,
+) -> bool {
+    let mut closed = Some(closed);
+    let mut interval = tokio::time::interval(CLOSE_POLL_INTERVAL);
+    loop {
+        if handle.is_closed() {
+            return true;
+        }
+        // in stdio mode, the channel is in the transport and only its stream is watched
+        tokio::select! {
+            _ = server_exited(exit) => return handle.is_closed(),
+            _ = stream_closed(&mut closed) => return handle.is_closed(),
+            _ = interval.tick() => (),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn supervise<H, T>(
+    connect: ConnectFn<H>,
+    launch: LaunchFn<H, T>,
+    backoff: Backoff,
+    on_event: EventFn,
+    tx: watch::Sender<T>,
+    handle: Handle<H>,
+    exit: Option<ServerExit>,
+    closed: oneshot::Receiver<()>,
+) where
+    H: Handler + 'static,
+{
+    let mut handle = Some(handle);
+    let mut launched = Some((exit, closed));
+    loop {
+        let connection_closed = match (handle.as_ref(), launched.take()) {
+            (Some(h), Some((exit, closed))) => {
+                let connection_closed = wait_disconnect(h, exit.as_ref(), closed).await;
+                // Only the stream is closed, so the old server may be still running.
+                // Stop it before another one is launched on the same connection.
+                if let Some(exit) = exit.filter(|_| !connection_closed) {
+                    let status = exit.shutdown(h, (), SHUTDOWN_TIMEOUT).await;
+                    debug!("old server is stopped: {:?}", status);
+                }
+                connection_closed
+            }
+            _ => true,
+        };
+        warn!("disconnected: connection_closed={}", connection_closed);
+        on_event(&SupervisorEvent::Disconnected { connection_closed });
+        if connection_closed {
+            handle = None;
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if backoff.max_attempts.is_some_and(|max| attempt > max) {
+                on_event(&SupervisorEvent::GaveUp);
+                return;
+            }
+            let delay = backoff.delay(attempt);
+            on_event(&SupervisorEvent::Reconnecting { attempt, delay });
+            tokio::time::sleep(delay).await;
+
+            // relaunch on the same connection if it is still alive
+            let h = match handle.take().filter(|h| !h.is_closed()) {
+                Some(h) => h,
+                None => match connect().await {
+                    Ok(h) => h,
+                    Err(e) => {
+                        debug!("reconnect failed: {}", e);
+                        on_event(&SupervisorEvent::ReconnectFailed {
+                            attempt,
+                            error: e.to_string(),
+                        });
+                        continue;
+                    }
+                },
+            };
+            match launch(&h).await {
+                Ok(Launched {
+                    exit,
+                    closed,
+                    client,
+                }) => {
+                    handle = Some(h);
+                    launched = Some((exit, closed));
+                    if tx.send(client).is_err() {
+                        // nobody uses the client anymore
+                        return;
+                    }
+                    on_event(&SupervisorEvent::Reconnected { attempt });
+                    break;
+                }
+                Err(e) => {
+                    debug!("relaunch failed: {}", e);
+                    on_event(&SupervisorEvent::ReconnectFailed {
+                        attempt,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            max_attempts: None,
+        };
+        let delays: Vec<u64> = (1..=6).map(|a| backoff.delay(a).as_secs()).collect();
+        assert_eq!(delays, [0, 1, 2, 4, 8, 10]);
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_watched_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let (mut stream, mut closed) = WatchedStream::new(client);
+        server.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(closed.try_recv().is_err());
+
+        drop(server);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(closed.await.is_ok());
+    }
+}
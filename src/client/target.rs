@@ -0,0 +1,147 @@
+//! Detection of the remote architecture and selection of the binary for it.
+use std::fmt;
+
+/// Shell script printing `uname -s`, `uname -m` and the libc (`gnu`, `musl` or `unknown`).
+pub(crate) const PROBE_SCRIPT: &str = r#"uname -s
+uname -m
+if ls /lib/ld-musl-* >/dev/null 2>&1 || ldd --version 2>&1 | grep -qi musl; then
+  echo musl
+elif ldd --version 2>&1 | grep -qiE 'glibc|gnu libc'; then
+  echo gnu
+else
+  echo unknown
+fi"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Libc {
+    Gnu,
+    Musl,
+    Unknown,
+}
+
+/// Operating system, architecture and libc of the remote host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RemoteTarget {
+    /// `uname -s` in lower case, e.g. `linux`
+    pub os: String,
+    /// `uname -m` normalized to the name used in target triples, e.g. `aarch64` for `arm64`
+    pub arch: String,
+    pub libc: Libc,
+}
+
+impl fmt::Display for RemoteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.arch, self.os, self.libc)
+    }
+}
+
+fn normalize_arch(arch: &str) -> &str {
+    match arch {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "i386" | "i486" | "i586" => "i686",
+        arch if arch.starts_with("armv7") => "armv7",
+        arch if arch.starts_with("armv6") => "arm",
+        arch => arch,
+    }
+}
+
+impl RemoteTarget {
+    /// Parse the output of `PROBE_SCRIPT`.
+    pub(crate) fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+        let os = lines.next()?.to_lowercase();
+        let arch = normalize_arch(lines.next()?).to_string();
+        let libc = lines
+            .next()
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(Libc::Unknown);
+        Some(Self { os, arch, libc })
+    }
+
+    /// Score how well a binary built for `triple` fits this target, `None` if it cannot run.
+    /// Exact arch beats a compatible one, and the native libc beats static musl on glibc hosts.
+    pub fn rank(&self, triple: &str) -> Option<u8> {
+        let mut parts = triple.split('-');
+        let arch = parts.next()?;
+        let rest: Vec<&str> = parts.collect();
+
+        let arch_score = if arch == self.arch {
+            2
+        } else {
+            let compatible = match self.arch.as_str() {
+                "armv7" => arch == "arm",
+                "i686" => arch == "i586",
+                _ => false,
+            };
+            if !compatible {
+                return None;
+            }
+            1
+        };
+
+        if !rest.iter().any(|p| *p == self.os) {
+            return None;
+        }
+
+        let env = rest.last().copied().unwrap_or_default();
+        let libc_score = if env.starts_with("musl") {
+            match self.libc {
+                Libc::Musl | Libc::Unknown => 2,
+                Libc::Gnu => 1,
+            }
+        } else if env.starts_with("gnu") {
+            match self.libc {
+                Libc::Gnu => 2,
+                Libc::Musl | Libc::Unknown => return None,
+            }
+        } else {
+            2
+        };
+        Some(arch_score * 3 + libc_score)
+    }
+
+    /// Choose the best triple from `triples` for this target.
+    pub fn select<'a, I>(&self, triples: I) -> Option<&'a str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        triples
+            .into_iter()
+            .filter_map(|t| self.rank(t).map(|rank| (rank, t)))
+            .max_by_key(|(rank, _)| *rank)
+            .map(|(_, t)| t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let triples = [
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+            "aarch64-unknown-linux-musl",
+            "arm-unknown-linux-gnueabihf",
+        ];
+
+        let target = RemoteTarget::parse("Linux\nx86_64\ngnu\n").unwrap();
+        assert_eq!(target.to_string(), "x86_64-linux-gnu");
+        assert_eq!(target.select(triples), Some("x86_64-unknown-linux-gnu"));
+
+        let target = RemoteTarget::parse("Linux\nx86_64\nmusl\n").unwrap();
+        assert_eq!(target.select(triples), Some("x86_64-unknown-linux-musl"));
+
+        let target = RemoteTarget::parse("Linux\narm64\ngnu\n").unwrap();
+        assert_eq!(target.select(triples), Some("aarch64-unknown-linux-musl"));
+
+        let target = RemoteTarget::parse("Linux\narmv7l\ngnu\n").unwrap();
+        assert_eq!(target.select(triples), Some("arm-unknown-linux-gnueabihf"));
+
+        let target = RemoteTarget::parse("Linux\nriscv64\nmusl\n").unwrap();
+        assert_eq!(target.select(triples), None);
+    }
+}
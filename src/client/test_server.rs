@@ -0,0 +1,204 @@
+//! In-process SSH server for tests.
+//!
+//! Commands are run with local `sh -c`, and `direct-tcpip` channels are forwarded to local ports.
+//! Like many OpenSSH versions, `signal` requests are ignored, and the server refuses `env`
+//! requests and the `sftp` subsystem, so the fallbacks of the client are used.
+use russh::server::{Auth, Msg, Session};
+use russh::{Channel, ChannelId, ChannelMsg, CryptoVec};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// Password accepted by the server, for any user.
+pub(crate) const PASSWORD: &str = "secret";
+
+struct Server;
+
+#[async_trait::async_trait]
+impl russh::server::Handler for Server {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, _user: &str, password: &str) -> Result<Auth, Self::Error> {
+        Ok(if password == PASSWORD {
+            Auth::Accept
+        } else {
+            Auth::Reject {
+                proceed_with_methods: None,
+            }
+        })
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        tokio::spawn(run_session(channel, session.handle()));
+        Ok(true)
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let addr = format!("{}:{}", host_to_connect, port_to_connect);
+        let (id, handle) = (channel.id(), session.handle());
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(channel.into_stream());
+            if let Ok(mut tcp) = tokio::net::TcpStream::connect(addr).await {
+                let (mut tcp_reader, mut tcp_writer) = tcp.split();
+                let upstream = async {
+                    let _ = tokio::io::copy(&mut reader, &mut tcp_writer).await;
+                    let _ = tcp_writer.shutdown().await;
+                    std::future::pending::<()>().await
+                };
+                // like sshd, close the channel when the server closes the connection
+                tokio::select! {
+                    _ = tokio::io::copy(&mut tcp_reader, &mut writer) => (),
+                    _ = upstream => (),
+                }
+            }
+            let _ = handle.eof(id).await;
+            let _ = handle.close(id).await;
+        });
+        Ok(true)
+    }
+}
+
+/// Send everything from `reader` as data (`ext` is `None`) or extended data of `id`.
+async fn pump<R: AsyncRead + Unpin>(
+    mut reader: R,
+    handle: russh::server::Handle,
+    id: ChannelId,
+    ext: Option<u32>,
+) {
+    let mut buf = vec![0; 8192];
+    while let Ok(n) = reader.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let data = CryptoVec::from_slice(&buf[..n]);
+        let sent = match ext {
+            Some(ext) => handle.extended_data(id, ext, data).await,
+            None => handle.data(id, data).await,
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+/// Run the command of the `exec` request on `channel` with `sh -c`.
+async fn run_session(mut channel: Channel<Msg>, handle: russh::server::Handle) {
+    let id = channel.id();
+    let command = loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Exec { command, .. }) => break command,
+            Some(ChannelMsg::SetEnv { .. }) | Some(ChannelMsg::RequestSubsystem { .. }) => {
+                let _ = handle.channel_failure(id).await;
+            }
+            Some(_) => (),
+            None => return,
+        }
+    };
+    let command = String::from_utf8_lossy(&command).into_owned();
+    let Ok(mut child) = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    else {
+        let _ = handle.channel_failure(id).await;
+        return;
+    };
+    let _ = handle.channel_success(id).await;
+
+    let mut stdin = child.stdin.take();
+    let stdout = tokio::spawn(pump(child.stdout.take().unwrap(), handle.clone(), id, None));
+    let stderr = tokio::spawn(pump(
+        child.stderr.take().unwrap(),
+        handle.clone(),
+        id,
+        Some(1),
+    ));
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status,
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    if let Some(writer) = stdin.as_mut() {
+                        if writer.write_all(&data).await.is_err() {
+                            stdin = None;
+                        }
+                    }
+                }
+                Some(ChannelMsg::Eof) => stdin = None,
+                Some(_) => (),
+                // the channel is closed by the client
+                None => return,
+            },
+        }
+    };
+    let _ = stdout.await;
+    let _ = stderr.await;
+    if let Some(code) = status.ok().and_then(|status| status.code()) {
+        let _ = handle.exit_status_request(id, code as u32).await;
+    }
+    let _ = handle.eof(id).await;
+    let _ = handle.close(id).await;
+}
+
+/// Start the server on a local port.
+pub(crate) async fn start() -> SocketAddr {
+    let config = Arc::new(russh::server::Config {
+        keys: vec![russh::keys::key::KeyPair::generate_ed25519()],
+        auth_rejection_time: std::time::Duration::ZERO,
+        auth_rejection_time_initial: Some(std::time::Duration::ZERO),
+        ..Default::default()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let _ = russh::server::run_stream(config.clone(), stream, Server).await;
+        }
+    });
+    addr
+}
+
+/// Client accepting any host key.
+pub(crate) struct Client;
+
+#[async_trait::async_trait]
+impl russh::client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        _server_public_key: &russh::keys::key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Start a server and connect to it.
+pub(crate) async fn connect() -> russh::client::Handle<Client> {
+    let addr = start().await;
+    let mut handle = russh::client::connect(Default::default(), addr, Client)
+        .await
+        .unwrap();
+    assert!(handle
+        .authenticate_password("user", PASSWORD)
+        .await
+        .unwrap());
+    handle
+}
@@ -1,5 +1,8 @@
 #![doc = include_str!("../README.md")]
+mod auth;
+pub mod build;
 pub mod client;
+pub mod remote_log;
 pub mod transport;
 pub use russh;
 
@@ -31,14 +34,16 @@ impl std::fmt::Display for Error {
     }
 }
 
-#[derive(Debug, PartialEq, Hash, strum::Display, strum::EnumString)]
+#[derive(Debug, Clone, Copy, PartialEq, Hash, strum::Display, strum::EnumString)]
 #[strum(serialize_all = "snake_case")]
 pub enum NetworkType {
     Tcp,
     Unix,
+    /// rpc frames are carried over stdin/stdout of the server process.
+    Stdio,
 }
 
-#[derive(Debug, PartialEq, Hash, strum::Display, strum::EnumString)]
+#[derive(Debug, Clone, Copy, PartialEq, Hash, strum::Display, strum::EnumString)]
 #[strum(serialize_all = "snake_case")]
 pub enum Protcol {
     Netrpc,
@@ -47,15 +52,189 @@ pub enum Protcol {
     TarpcBincode,
 }
 
+/// Environment variable to tell the server which app protocol versions the client supports.
+/// The value is the text form of `AppProtocolVersions`, like `1-3,5`.
+pub const APP_PROTOCOL_VERSIONS_ENV: &str = "SSHRPC_APP_PROTOCOL_VERSIONS";
+
+/// Environment variable to give the auth token to the server.
+/// The client sets this through stdin of the server, so it does not appear on the command line.
+pub const AUTH_TOKEN_ENV: &str = "SSHRPC_AUTH_TOKEN";
+
+/// Environment variable to ask the server to remove its own binary after start.
+/// The client sets this to `1` when the binary is written to a tmp file.
+pub const UNLINK_SELF_ENV: &str = "SSHRPC_UNLINK_SELF";
+
+/// Set of app protocol versions.
+/// This can be made from a version, a range of versions or a list of versions.
+/// Versions are kept as ranges, so even `0..=u32::MAX` is small.
+/// The text form is a comma separated list of versions and ranges like `1-3,5`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AppProtocolVersions(Vec<(u32, u32)>);
+
+impl AppProtocolVersions {
+    /// Sort and merge `(start, end)` ranges. Empty ranges are dropped.
+    fn from_ranges<I: IntoIterator<Item = (u32, u32)>>(ranges: I) -> Self {
+        let mut ranges: Vec<(u32, u32)> = ranges
+            .into_iter()
+            .filter(|(start, end)| start <= end)
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        AppProtocolVersions(merged)
+    }
+
+    pub fn contains(&self, version: u32) -> bool {
+        self.0
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&version))
+    }
+
+    pub fn highest(&self) -> Option<u32> {
+        self.0.last().map(|(_, end)| *end)
+    }
+
+    /// The highest version contained in both sets.
+    pub fn highest_common(&self, other: &AppProtocolVersions) -> Option<u32> {
+        self.0
+            .iter()
+            .flat_map(|a| {
+                other.0.iter().filter_map(move |b| {
+                    let (start, end) = (a.0.max(b.0), a.1.min(b.1));
+                    (start <= end).then_some(end)
+                })
+            })
+            .max()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().flat_map(|(start, end)| *start..=*end)
+    }
+}
+
+impl FromIterator<u32> for AppProtocolVersions {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        Self::from_ranges(iter.into_iter().map(|v| (v, v)))
+    }
+}
+
+impl From<u32> for AppProtocolVersions {
+    fn from(version: u32) -> Self {
+        AppProtocolVersions(vec![(version, version)])
+    }
+}
+
+impl From<std::ops::RangeInclusive<u32>> for AppProtocolVersions {
+    fn from(versions: std::ops::RangeInclusive<u32>) -> Self {
+        Self::from_ranges([versions.into_inner()])
+    }
+}
+
+impl From<&[u32]> for AppProtocolVersions {
+    fn from(versions: &[u32]) -> Self {
+        versions.iter().copied().collect()
+    }
+}
+
+impl<const N: usize> From<[u32; N]> for AppProtocolVersions {
+    fn from(versions: [u32; N]) -> Self {
+        versions.into_iter().collect()
+    }
+}
+
+impl From<Vec<u32>> for AppProtocolVersions {
+    fn from(versions: Vec<u32>) -> Self {
+        versions.into_iter().collect()
+    }
+}
+
+impl std::fmt::Display for AppProtocolVersions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let versions: Vec<String> = self
+            .0
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}-{}", start, end)
+                }
+            })
+            .collect();
+        write!(f, "{}", versions.join(","))
+    }
+}
+
+impl std::str::FromStr for AppProtocolVersions {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(|v| match v.split_once('-') {
+                Some((start, end)) => Ok((start.trim().parse()?, end.trim().parse()?)),
+                None => v.parse().map(|v| (v, v)),
+            })
+            .collect::<Result<Vec<(u32, u32)>, _>>()?;
+        Ok(Self::from_ranges(ranges))
+    }
+}
+
+/// Address the rpc server is listening on.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum NetworkAddr {
+    Tcp(std::net::SocketAddr),
+    /// Path of a unix domain socket on the remote host.
+    Unix(std::path::PathBuf),
+    /// No address, the exec channel itself is used.
+    Stdio,
+}
+
+impl std::fmt::Display for NetworkAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetworkAddr::Tcp(addr) => write!(f, "{}", addr),
+            NetworkAddr::Unix(path) => write!(f, "{}", path.display()),
+            NetworkAddr::Stdio => Ok(()),
+        }
+    }
+}
+
 /// This is go-plugin like handshake information.
-#[derive(Debug, PartialEq, Hash)]
+#[derive(Debug, Clone, PartialEq, Hash)]
 pub struct HandshakeInformation {
     /// Currently, core_protcol_version is always 1.
     pub core_protcol_version: u32,
     pub app_protocol_version: u32,
     pub network_type: NetworkType,
-    pub network_addr: std::net::SocketAddr,
+    pub network_addr: NetworkAddr,
     pub protcol: Protcol,
+    /// base64 encoded DER certificate of the server (go-plugin AutoMTLS).
+    pub server_cert: Option<String>,
+    /// pid of the server, used to kill it when the SSH `signal` request is ignored.
+    pub pid: Option<u32>,
+}
+
+impl HandshakeInformation {
+    /// Decode `server_cert` into DER.
+    /// go-plugin encodes it without padding, but padded one is also accepted.
+    pub fn server_cert_der(&self) -> Option<Result<Vec<u8>, base64::DecodeError>> {
+        use base64::engine::{general_purpose, DecodePaddingMode, GeneralPurpose};
+        use base64::Engine;
+
+        const ENGINE: GeneralPurpose = GeneralPurpose::new(
+            &base64::alphabet::STANDARD,
+            general_purpose::NO_PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+        );
+        self.server_cert.as_ref().map(|cert| ENGINE.decode(cert))
+    }
 }
 
 impl std::fmt::Display for HandshakeInformation {
@@ -68,7 +247,14 @@ impl std::fmt::Display for HandshakeInformation {
             self.network_type,
             self.network_addr,
             self.protcol
-        )
+        )?;
+        if self.server_cert.is_some() || self.pid.is_some() {
+            write!(f, "|{}", self.server_cert.as_deref().unwrap_or_default())?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, "|{}", pid)?;
+        }
+        Ok(())
     }
 }
 
@@ -101,8 +287,23 @@ impl std::str::FromStr for HandshakeInformation {
             .next()
             .ok_or(InsufficientFields)?
             .parse::<NetworkType>()?;
-        let network_addr = parts.next().ok_or(InsufficientFields)?.parse()?;
+        let network_addr = parts.next().ok_or(InsufficientFields)?;
+        let network_addr = match network_type {
+            NetworkType::Tcp => NetworkAddr::Tcp(network_addr.parse()?),
+            NetworkType::Unix => NetworkAddr::Unix(network_addr.into()),
+            NetworkType::Stdio => NetworkAddr::Stdio,
+        };
         let protcol = parts.next().ok_or(InsufficientFields)?.parse::<Protcol>()?;
+        // go-plugin always prints this field, but it is empty without AutoMTLS
+        let server_cert = parts
+            .next()
+            .filter(|cert| !cert.is_empty())
+            .map(|cert| cert.to_string());
+        let pid = parts
+            .next()
+            .filter(|pid| !pid.is_empty())
+            .map(str::parse::<u32>)
+            .transpose()?;
 
         Ok(HandshakeInformation {
             core_protcol_version,
@@ -110,6 +311,8 @@ impl std::str::FromStr for HandshakeInformation {
             network_type,
             network_addr,
             protcol,
+            server_cert,
+            pid,
         })
     }
 }
@@ -134,8 +337,10 @@ mod tests {
                 core_protcol_version: 1,
                 app_protocol_version: 1,
                 network_type: NetworkType::Tcp,
-                network_addr: "127.0.0.1:1234".parse().unwrap(),
+                network_addr: NetworkAddr::Tcp("127.0.0.1:1234".parse().unwrap()),
                 protcol: Protcol::TarpcBincode,
+                server_cert: None,
+                pid: None,
             },
             "1|1|tcp|127.0.0.1:1234|tarpc<bincode>"
                 .parse::<HandshakeInformation>()
@@ -147,13 +352,94 @@ mod tests {
                 core_protcol_version: 1,
                 app_protocol_version: 1,
                 network_type: NetworkType::Tcp,
-                network_addr: std::net::SocketAddr::new(
+                network_addr: NetworkAddr::Tcp(std::net::SocketAddr::new(
                     std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
                     1234
-                ),
+                )),
                 protcol: Protcol::Grpc,
+                server_cert: None,
+                pid: None,
             }
             .to_string()
         );
     }
+
+    #[test]
+    fn test_parse_handshake_unix() {
+        let info = "1|1|unix|/tmp/sshrpc-1/rpc.sock|tarpc<bincode>"
+            .parse::<HandshakeInformation>()
+            .unwrap();
+        assert_eq!(info.network_type, NetworkType::Unix);
+        assert_eq!(
+            info.network_addr,
+            NetworkAddr::Unix("/tmp/sshrpc-1/rpc.sock".into())
+        );
+        assert_eq!(
+            info.to_string(),
+            "1|1|unix|/tmp/sshrpc-1/rpc.sock|tarpc<bincode>"
+        );
+    }
+
+    #[test]
+    fn test_parse_handshake_stdio() {
+        let info = "1|1|stdio||tarpc<bincode>"
+            .parse::<HandshakeInformation>()
+            .unwrap();
+        assert_eq!(info.network_type, NetworkType::Stdio);
+        assert_eq!(info.network_addr, NetworkAddr::Stdio);
+        assert_eq!(info.to_string(), "1|1|stdio||tarpc<bincode>");
+    }
+
+    #[test]
+    fn test_parse_handshake_go_plugin() {
+        let info = "1|2|unix|/tmp/plugin123|grpc|"
+            .parse::<HandshakeInformation>()
+            .unwrap();
+        assert_eq!(info.protcol, Protcol::Grpc);
+        assert_eq!(info.server_cert, None);
+
+        let info = "1|2|tcp|127.0.0.1:10000|grpc|AQID"
+            .parse::<HandshakeInformation>()
+            .unwrap();
+        assert_eq!(info.server_cert.as_deref(), Some("AQID"));
+        assert_eq!(info.server_cert_der().unwrap().unwrap(), vec![1, 2, 3]);
+        assert_eq!(info.to_string(), "1|2|tcp|127.0.0.1:10000|grpc|AQID");
+    }
+
+    #[test]
+    fn test_parse_handshake_pid() {
+        let info = "1|1|tcp|127.0.0.1:1234|tarpc<bincode>||4321"
+            .parse::<HandshakeInformation>()
+            .unwrap();
+        assert_eq!(info.server_cert, None);
+        assert_eq!(info.pid, Some(4321));
+        assert_eq!(
+            info.to_string(),
+            "1|1|tcp|127.0.0.1:1234|tarpc<bincode>||4321"
+        );
+        assert!("1|1|tcp|127.0.0.1:1234|tarpc<bincode>||x"
+            .parse::<HandshakeInformation>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_app_protocol_versions() {
+        let server = AppProtocolVersions::from(1..=3);
+        let client = "2, 3,5".parse::<AppProtocolVersions>().unwrap();
+        assert_eq!(client.to_string(), "2-3,5");
+        assert_eq!(server.highest_common(&client), Some(3));
+        assert_eq!(server.highest_common(&AppProtocolVersions::from(4)), None);
+        assert!(AppProtocolVersions::from([3, 1]).contains(1));
+        assert_eq!(AppProtocolVersions::from(vec![2, 7, 2]).highest(), Some(7));
+
+        let all = AppProtocolVersions::from(0..=u32::MAX);
+        assert_eq!(all.to_string(), format!("0-{}", u32::MAX));
+        assert_eq!(all.to_string().parse::<AppProtocolVersions>().unwrap(), all);
+        assert_eq!(all.highest_common(&client), Some(5));
+        assert_eq!(
+            "1-3, 7 ,2-5".parse::<AppProtocolVersions>().unwrap(),
+            AppProtocolVersions::from([1, 2, 3, 4, 5, 7])
+        );
+        assert!("1-x".parse::<AppProtocolVersions>().is_err());
+    }
 }
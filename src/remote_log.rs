@@ -0,0 +1,394 @@
+//! Forwarding of server side tracing events to the client.
+//!
+//! The server writes each event as a JSON line to stderr with `JsonStderrLayer`,
+//! and the client re-emits it as a tracing event with the original level, target and fields.
+//! Field values are recorded as strings. After `MAX_CALLSITES` distinct targets and sets of
+//! field names, events are re-emitted with `FALLBACK_TARGET` instead.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::callsite::{Callsite, Identifier};
+use tracing::field::{Field, FieldSet, Visit};
+use tracing::metadata::Kind;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// One tracing event of the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LogRecord {
+    /// marks the line as a record, to tell it from other stderr output
+    sshrpc_log: u32,
+    level: String,
+    target: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, String>,
+}
+
+impl LogRecord {
+    fn parse(line: &str) -> Option<Self> {
+        if !line.starts_with('{') {
+            return None;
+        }
+        serde_json::from_str::<Self>(line)
+            .ok()
+            .filter(|record| record.sshrpc_log == 1)
+    }
+}
+
+#[derive(Default)]
+struct RecordVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Visit for RecordVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+}
+
+/// Layer writing events as JSON lines to stderr, for the client to re-emit them.
+///
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(sshrpc::remote_log::JsonStderrLayer::new())
+///     .init();
+/// ```
+pub struct JsonStderrLayer {
+    max_level: Level,
+}
+
+impl Default for JsonStderrLayer {
+    fn default() -> Self {
+        Self {
+            max_level: Level::INFO,
+        }
+    }
+}
+
+impl JsonStderrLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Most verbose level to forward (default: `Level::INFO`).
+    pub fn max_level(mut self, level: Level) -> Self {
+        self.max_level = level;
+        self
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JsonStderrLayer {
+    // `max_level` is checked here, not in `enabled`, which would disable the events for the
+    // other layers too.
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if *event.metadata().level() > self.max_level {
+            return;
+        }
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+        let record = LogRecord {
+            sshrpc_log: 1,
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        };
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(std::io::stderr().lock(), "{}", line);
+        }
+    }
+}
+
+/// Max number of callsites made for remote events.
+/// Each one is leaked, so events of new targets or field names beyond this use `FALLBACK_TARGET`.
+const MAX_CALLSITES: usize = 256;
+
+/// Target of remote events which do not get their own callsite.
+/// The original target and the fields are recorded in the `target` and `fields` fields.
+pub const FALLBACK_TARGET: &str = "sshrpc::remote_log";
+
+/// Max number of fields of an event, including `message` (the limit of `tracing::ValueSet`).
+const MAX_FIELDS: usize = 32;
+
+/// Callsite made at runtime, because the target of a remote event is not known at compile time.
+struct DynCallsite {
+    metadata: OnceLock<Metadata<'static>>,
+}
+
+impl Callsite for DynCallsite {
+    fn set_interest(&self, _: tracing::subscriber::Interest) {}
+
+    fn metadata(&self) -> &Metadata<'static> {
+        self.metadata.get().expect("metadata is set on creation")
+    }
+}
+
+type CallsiteKey = (Level, String, Vec<String>);
+
+/// Callsite for `level`, `target` and the field `names` (after `message`).
+/// These are leaked and cached, and `None` is returned when `bounded` and `MAX_CALLSITES`
+/// are made already.
+fn callsite(
+    level: Level,
+    target: &str,
+    names: &[&str],
+    bounded: bool,
+) -> Option<&'static DynCallsite> {
+    static CALLSITES: OnceLock<Mutex<HashMap<CallsiteKey, &'static DynCallsite>>> = OnceLock::new();
+    let mut callsites = CALLSITES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let key = (
+        level,
+        target.to_string(),
+        names.iter().map(|name| name.to_string()).collect(),
+    );
+    if let Some(callsite) = callsites.get(&key) {
+        return Some(callsite);
+    }
+    if bounded && callsites.len() >= MAX_CALLSITES {
+        return None;
+    }
+    let callsite: &'static DynCallsite = Box::leak(Box::new(DynCallsite {
+        metadata: OnceLock::new(),
+    }));
+    let field_names: Vec<&'static str> = std::iter::once("message")
+        .chain(
+            names
+                .iter()
+                .map(|name| &*Box::leak(name.to_string().into_boxed_str())),
+        )
+        .collect();
+    let _ = callsite.metadata.set(Metadata::new(
+        "remote event",
+        Box::leak(target.to_string().into_boxed_str()),
+        level,
+        None,
+        None,
+        None,
+        FieldSet::new(
+            Box::leak(field_names.into_boxed_slice()),
+            Identifier(callsite),
+        ),
+        Kind::EVENT,
+    ));
+    tracing::callsite::register(callsite);
+    callsites.insert(key, callsite);
+    Some(callsite)
+}
+
+/// Dispatch an event of `callsite` with `message` and `values` of its fields.
+fn dispatch<'a, I>(callsite: &'static DynCallsite, message: &str, values: I)
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let metadata = callsite.metadata();
+    if !tracing::dispatcher::get_default(|d| d.enabled(metadata)) {
+        return;
+    }
+    let fields = metadata.fields();
+    let Some(message_field) = fields.field("message") else {
+        return;
+    };
+    let values: Vec<(Field, &str)> = values
+        .into_iter()
+        .filter_map(|(name, value)| Some((fields.field(name)?, value)))
+        .collect();
+    // unused slots repeat `message` without value, which is not recorded
+    let mut slots = [(&message_field, None::<&dyn tracing::Value>); MAX_FIELDS];
+    slots[0].1 = Some(&message);
+    for (slot, (field, value)) in slots[1..].iter_mut().zip(&values) {
+        *slot = (field, Some(value));
+    }
+    Event::dispatch(metadata, &fields.value_set(&slots));
+}
+
+fn emit(record: &LogRecord) {
+    let Ok(level) = record.level.parse::<Level>() else {
+        return;
+    };
+    let names: Vec<&str> = record.fields.keys().map(String::as_str).collect();
+    let own = if names.len() < MAX_FIELDS {
+        callsite(level, &record.target, &names, true)
+    } else {
+        None
+    };
+    if let Some(callsite) = own {
+        let values = record.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        dispatch(callsite, &record.message, values);
+        return;
+    }
+
+    let Some(callsite) = callsite(level, FALLBACK_TARGET, &["target", "fields"], false) else {
+        return;
+    };
+    let mut fields = String::new();
+    for (key, value) in &record.fields {
+        let sep = if fields.is_empty() { "" } else { " " };
+        let _ = write!(fields, "{}{}={}", sep, key, value);
+    }
+    let values = [
+        ("target", record.target.as_str()),
+        ("fields", fields.as_str()),
+    ];
+    dispatch(callsite, &record.message, values);
+}
+
+/// Re-emits stderr of a server on the client.
+/// JSON lines of `JsonStderrLayer` become tracing events, and other lines are logged as warnings.
+/// Events are emitted in a `sshrpc_server` span with `host` and `session` fields.
+pub(crate) struct ServerLog {
+    span: tracing::Span,
+    buf: Vec<u8>,
+}
+
+impl ServerLog {
+    pub(crate) fn new(host: &str) -> Self {
+        static SESSION: AtomicU64 = AtomicU64::new(0);
+        let session = SESSION.fetch_add(1, Ordering::Relaxed);
+        Self {
+            span: tracing::info_span!("sshrpc_server", host = %host, session),
+            buf: vec![],
+        }
+    }
+
+    /// Feed stderr data of the server.
+    pub(crate) fn stderr(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        let _enter = self.span.enter();
+        while let Some(pos) = self.buf.iter().position(|&c| c == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            match LogRecord::parse(line) {
+                Some(record) => emit(&record),
+                None => tracing::warn!("stderr: {}", line),
+            }
+        }
+    }
+
+    /// Feed stdout data of the server.
+    pub(crate) fn stdout(&mut self, data: &[u8]) {
+        let _enter = self.span.enter();
+        let line = String::from_utf8_lossy(data);
+        tracing::debug!("stdout: {}", line.strip_suffix('\n').unwrap_or(&line));
+    }
+
+    /// Log the message of the exec channel.
+    pub(crate) fn channel_msg(&mut self, msg: &russh::ChannelMsg) {
+        match msg {
+            russh::ChannelMsg::Data { data } => self.stdout(data),
+            russh::ChannelMsg::ExtendedData { data, ext: 1 } => self.stderr(data),
+            msg => {
+                if let Some(exit) = crate::client::ExitStatus::from_msg(msg) {
+                    let _enter = self.span.enter();
+                    tracing::debug!("exit: {}", exit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_record() {
+        let record = LogRecord {
+            sshrpc_log: 1,
+            level: "WARN".to_string(),
+            target: "server::db".to_string(),
+            message: "slow query".to_string(),
+            fields: [("ms".to_string(), "1200".to_string())].into(),
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(LogRecord::parse(&line), Some(record));
+        assert_eq!(LogRecord::parse("plain text"), None);
+        assert_eq!(LogRecord::parse("{\"other\":1}"), None);
+    }
+
+    /// Layer keeping the target and the fields of each event.
+    #[derive(Clone, Default)]
+    struct Capture(std::sync::Arc<Mutex<Vec<(String, RecordVisitor)>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let mut visitor = RecordVisitor::default();
+            event.record(&mut visitor);
+            let target = event.metadata().target().to_string();
+            self.0.lock().unwrap().push((target, visitor));
+        }
+    }
+
+    #[test]
+    fn test_json_stderr_layer_keeps_other_layers() {
+        use tracing_subscriber::prelude::*;
+
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(JsonStderrLayer::new())
+            .with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("below max_level");
+        });
+        assert_eq!(capture.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_emit() {
+        use tracing_subscriber::prelude::*;
+
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(capture.clone())
+            .with(tracing_subscriber::filter::LevelFilter::INFO);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut record = LogRecord {
+                sshrpc_log: 1,
+                level: "WARN".to_string(),
+                target: "server::db".to_string(),
+                message: "slow query".to_string(),
+                fields: [
+                    ("ms".to_string(), "1200".to_string()),
+                    ("table".to_string(), "users".to_string()),
+                ]
+                .into(),
+            };
+            emit(&record);
+            // disabled by the level filter
+            record.level = "DEBUG".to_string();
+            emit(&record);
+        });
+
+        let events = capture.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (target, event) = &events[0];
+        assert_eq!(target, "server::db");
+        assert_eq!(event.message, "slow query");
+        assert_eq!(event.fields["ms"], "1200");
+        assert_eq!(event.fields["table"], "users");
+    }
+}
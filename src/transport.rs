@@ -1,52 +1,354 @@
-use crate::{HandshakeInformation, NetworkType, Protcol};
+mod incoming;
+mod serve;
+
+pub use incoming::{AuthIncoming, Incoming, Listener};
+#[cfg(test)]
+pub(crate) use serve::serve_incoming;
+pub use serve::{serve, serve_with, ServeOptions};
+
+use crate::{
+    AppProtocolVersions, HandshakeInformation, NetworkAddr, NetworkType, Protcol,
+    APP_PROTOCOL_VERSIONS_ENV, AUTH_TOKEN_ENV, UNLINK_SELF_ENV,
+};
 use serde::{Deserialize, Serialize};
 use std::io::Write;
-use tarpc::serde_transport::tcp::Incoming;
 use tarpc::serde_transport::Transport;
 use tarpc::tokio_serde::formats::Bincode;
-use tokio::io::{AsyncRead, AsyncWrite};
+
+/// tarpc transport with bincode serialization, as used on both sides of the connection.
+pub type BincodeTransport<S, Item, SinkItem> =
+    Transport<S, Item, SinkItem, Bincode<Item, SinkItem>>;
+
+/// Choose the app protocol version to serve.
+/// This is the highest version supported by both of the server and the client.
+/// The client advertises its versions with `APP_PROTOCOL_VERSIONS_ENV`.
+/// If the client does not advertise or there is no common version,
+/// the highest version of the server is chosen and the client reports the mismatch.
+pub fn negotiate_app_protocol_version(
+    supported: &AppProtocolVersions,
+) -> Result<u32, std::io::Error> {
+    let highest = supported.highest().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no app protocol version is supported",
+        )
+    })?;
+    let client = std::env::var(APP_PROTOCOL_VERSIONS_ENV)
+        .ok()
+        .and_then(|versions| versions.parse::<AppProtocolVersions>().ok());
+    Ok(client
+        .and_then(|client| supported.highest_common(&client))
+        .unwrap_or(highest))
+}
+
+/// Auth token taken by `take_auth_token`
+static AUTH_TOKEN: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+/// Take the auth token given by the client (see `AUTH_TOKEN_ENV`) out of the environment,
+/// so child processes of the server do not inherit it.
+/// Call this at the start of `main` before the tokio runtime starts,
+/// because removing an environment variable is unsound while other threads may read it.
+/// The token is kept for the listeners, so calling this again returns the same token.
+pub fn take_auth_token() -> Option<String> {
+    AUTH_TOKEN
+        .get_or_init(|| {
+            let token = std::env::var(AUTH_TOKEN_ENV).ok();
+            std::env::remove_var(AUTH_TOKEN_ENV);
+            token.filter(|token| !token.is_empty())
+        })
+        .clone()
+}
+
+/// Auth token for the listeners.
+/// If `take_auth_token` was not called, the token is read but left in the environment.
+fn auth_token() -> Option<String> {
+    AUTH_TOKEN.get().cloned().unwrap_or_else(|| {
+        let token = std::env::var(AUTH_TOKEN_ENV).ok();
+        if token.is_some() {
+            tracing::warn!(
+                "{} is left in the environment, call `take_auth_token` at the start of `main`",
+                AUTH_TOKEN_ENV
+            );
+        }
+        token.filter(|token| !token.is_empty())
+    })
+}
+
+/// Request to remove the binary taken by `take_unlink_self`
+static UNLINK_SELF: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+/// Take the request of the client to remove the binary of the server (see `UNLINK_SELF_ENV`)
+/// out of the environment, so child processes do not remove their own binaries.
+/// Call this at the start of `main` together with `take_auth_token`, for the same reason.
+/// The binary is removed when the handshake information is printed.
+pub fn take_unlink_self() -> bool {
+    *UNLINK_SELF.get_or_init(|| {
+        let unlink = std::env::var(UNLINK_SELF_ENV);
+        std::env::remove_var(UNLINK_SELF_ENV);
+        unlink.as_deref() == Ok("1")
+    })
+}
+
+/// Remove the binary of the current process if the client asks to.
+/// The running process is not affected, and no tmp file is left even if the client crashes.
+/// If `take_unlink_self` was not called, the request is read but left in the environment.
+fn unlink_self() {
+    let unlink = UNLINK_SELF.get().copied().unwrap_or_else(|| {
+        let unlink = std::env::var(UNLINK_SELF_ENV).is_ok_and(|unlink| unlink == "1");
+        if unlink {
+            tracing::warn!(
+                "{} is left in the environment, call `take_unlink_self` at the start of `main`",
+                UNLINK_SELF_ENV
+            );
+        }
+        unlink
+    });
+    if !unlink {
+        return;
+    }
+    if let Err(e) = std::env::current_exe().and_then(std::fs::remove_file) {
+        tracing::debug!("failed to remove own binary: {}", e);
+    }
+}
+
+fn print_handshake_information(info: &HandshakeInformation) -> Result<(), std::io::Error> {
+    unlink_self();
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_fmt(format_args!("{}\n", info))?;
+    stdout.flush()
+}
 
 /// create listener
 /// This function is print the information to client, so you don't need care it.
+/// `app_protocol_version` can be a set of versions, see `negotiate_app_protocol_version`.
+/// When the client gave an auth token, connections without it are rejected.
 pub async fn listen<Item, SinkItem>(
-    app_protocol_version: u32,
-) -> Result<
-    Incoming<Item, SinkItem, Bincode<Item, SinkItem>, impl Fn() -> Bincode<Item, SinkItem>>,
-    std::io::Error,
->
+    app_protocol_version: impl Into<AppProtocolVersions>,
+) -> Result<Incoming<tokio::net::TcpListener, Item, SinkItem>, std::io::Error>
 where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let addr = "127.0.0.1:0".parse::<std::net::SocketAddr>().unwrap();
-    let listener: Incoming<Item, SinkItem, _, _> =
-        tarpc::serde_transport::tcp::listen(&addr, Bincode::default).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
 
-    let info = HandshakeInformation {
+    print_handshake_information(&HandshakeInformation {
         core_protcol_version: 1,
-        app_protocol_version,
+        app_protocol_version: negotiate_app_protocol_version(&app_protocol_version.into())?,
         network_type: NetworkType::Tcp,
-        network_addr: listener.local_addr(),
+        network_addr: NetworkAddr::Tcp(listener.local_addr()?),
         protcol: Protcol::TarpcBincode,
-    };
+        server_cert: None,
+        pid: Some(std::process::id()),
+    })?;
+    Ok(Incoming::new(listener, auth_token()))
+}
+
+/// Directory removed with its contents on drop, so no stale socket is left after the server exits.
+pub(crate) struct PrivateDir(std::path::PathBuf);
 
-    std::io::stdout()
-        .lock()
-        .write_fmt(format_args!("{}\n", info))?;
-    std::io::stdout().lock().flush()?;
-    Ok(listener)
+impl PrivateDir {
+    fn path(&self) -> &std::path::Path {
+        &self.0
+    }
 }
 
-pub(crate) fn stream2transport<S, Item, SinkItem>(
-    stream: S,
-) -> Transport<S, Item, SinkItem, Bincode<Item, SinkItem>>
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Create a private directory (mode 0700) for the unix domain socket.
+/// The directory must not exist yet, so nobody else can prepare it for us.
+#[cfg(unix)]
+fn create_private_dir() -> Result<PrivateDir, std::io::Error> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join(format!("sshrpc-{}-{}", std::process::id(), nanos));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(PrivateDir(dir))
+}
+
+/// create listener on unix domain socket
+/// The socket is placed in a private directory, so other local users cannot connect to it.
+/// The client reaches it through ssh streamlocal forwarding.
+/// The directory is removed when the returned `Incoming` is dropped.
+#[cfg(unix)]
+pub async fn listen_unix<Item, SinkItem>(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+) -> Result<Incoming<tokio::net::UnixListener, Item, SinkItem>, std::io::Error>
 where
-    S: AsyncWrite + AsyncRead,
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    tarpc::serde_transport::new(
-        tokio_util::codec::Framed::new(stream, tokio_util::codec::LengthDelimitedCodec::new()),
-        Bincode::default(),
+    let dir = create_private_dir()?;
+    let path = dir.path().join("rpc.sock");
+    let listener = tokio::net::UnixListener::bind(&path)?;
+
+    print_handshake_information(&HandshakeInformation {
+        core_protcol_version: 1,
+        app_protocol_version: negotiate_app_protocol_version(&app_protocol_version.into())?,
+        network_type: NetworkType::Unix,
+        network_addr: NetworkAddr::Unix(path),
+        protcol: Protcol::TarpcBincode,
+        server_cert: None,
+        pid: Some(std::process::id()),
+    })?;
+    Ok(Incoming::new(listener, auth_token()).private_dir(dir))
+}
+
+/// stdin and stdout of the current process as one stream.
+pub type Stdio = tokio::io::Join<tokio::io::Stdin, tokio::io::Stdout>;
+
+/// create transport on stdin/stdout
+/// This is for hosts that do not allow port forwarding (e.g. `AllowTcpForwarding no`).
+/// After the handshake line, stdout must only be written by the returned transport,
+/// so use stderr for logging.
+/// The client must launch the server with `SshRpcExt::exec_rpc_server_stdio`.
+/// The auth token is not checked, because nobody else can connect to stdin,
+/// but call `take_auth_token` anyway so child processes do not inherit it.
+pub fn listen_stdio<Item, SinkItem>(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+) -> Result<BincodeTransport<Stdio, Item, SinkItem>, std::io::Error>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    listen_stdio_with(
+        app_protocol_version,
+        &tokio_util::codec::LengthDelimitedCodec::builder(),
     )
 }
+
+fn listen_stdio_with<Item, SinkItem>(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+    config: &tokio_util::codec::length_delimited::Builder,
+) -> Result<BincodeTransport<Stdio, Item, SinkItem>, std::io::Error>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    // Only warn if the token was not taken.
+    let _ = auth_token();
+    print_handshake_information(&HandshakeInformation {
+        core_protcol_version: 1,
+        app_protocol_version: negotiate_app_protocol_version(&app_protocol_version.into())?,
+        network_type: NetworkType::Stdio,
+        network_addr: NetworkAddr::Stdio,
+        protcol: Protcol::TarpcBincode,
+        server_cert: None,
+        pid: Some(std::process::id()),
+    })?;
+    Ok(tarpc::serde_transport::new(
+        config.new_framed(tokio::io::join(tokio::io::stdin(), tokio::io::stdout())),
+        Bincode::default(),
+    ))
+}
+
+/// create grpc listener
+/// This function is print the information to client, so you don't need care it.
+/// Pass the returned stream to `tonic::transport::server::Router::serve_with_incoming`.
+#[cfg(feature = "grpc")]
+pub async fn listen_grpc(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+) -> Result<AuthIncoming<tokio::net::TcpListener>, std::io::Error> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+    print_handshake_information(&HandshakeInformation {
+        core_protcol_version: 1,
+        app_protocol_version: negotiate_app_protocol_version(&app_protocol_version.into())?,
+        network_type: NetworkType::Tcp,
+        network_addr: NetworkAddr::Tcp(listener.local_addr()?),
+        protcol: Protcol::Grpc,
+        server_cert: None,
+        pid: Some(std::process::id()),
+    })?;
+    Ok(AuthIncoming::new(listener, auth_token()))
+}
+
+/// serve tonic services
+/// `router` is usually made by `tonic::transport::Server::builder().add_service(service)`.
+#[cfg(feature = "grpc")]
+pub async fn serve_grpc(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+    router: tonic::transport::server::Router,
+) -> Result<(), std::io::Error> {
+    let incoming = listen_grpc(app_protocol_version).await?;
+    router
+        .serve_with_incoming(incoming)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_unix_removes_dir() {
+        let incoming = listen_unix::<(), ()>(1).await.unwrap();
+        let path = incoming.get_ref().local_addr().unwrap();
+        let dir = path.as_pathname().unwrap().parent().unwrap().to_path_buf();
+        assert!(dir.exists());
+        drop(incoming);
+        assert!(!dir.exists());
+    }
+
+    /// Call an unknown method, which the server answers with `Unimplemented`.
+    #[cfg(feature = "grpc")]
+    async fn grpc_call(
+        addr: std::net::SocketAddr,
+        token: Option<&str>,
+    ) -> Result<tonic::codegen::http::Response<tonic::body::BoxBody>, Box<dyn std::error::Error>>
+    {
+        use crate::client::SshRpcSession;
+        use tower::ServiceExt;
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let session = SshRpcSession::<(), _> {
+            handshake_information: HandshakeInformation {
+                core_protcol_version: 1,
+                app_protocol_version: 1,
+                network_type: NetworkType::Tcp,
+                network_addr: NetworkAddr::Tcp(addr),
+                protcol: Protcol::Grpc,
+                server_cert: None,
+                pid: None,
+            },
+            channel: None,
+            stream,
+            auth_token: token.map(str::to_string),
+            launch_method: None,
+        };
+        let (_, channel) = session.try_into_grpc_channel(1).await?;
+        let request = tonic::codegen::http::Request::builder()
+            .method("POST")
+            .uri("http://localhost/sshrpc.Test/Call")
+            .header("content-type", "application/grpc")
+            .body(tonic::body::empty_body())?;
+        Ok(channel.oneshot(request).await?)
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_auth_incoming() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = AuthIncoming::new(listener, Some("secret".to_string()));
+        let router =
+            tonic::transport::Server::builder().add_routes(tonic::service::Routes::default());
+        let server = tokio::spawn(router.serve_with_incoming(incoming));
+
+        let response = grpc_call(addr, Some("secret")).await.unwrap();
+        // 12: Unimplemented, the connection is served
+        assert_eq!(response.headers()["grpc-status"], "12");
+        assert!(grpc_call(addr, Some("wrong")).await.is_err());
+        assert!(grpc_call(addr, None).await.is_err());
+        server.abort();
+    }
+}
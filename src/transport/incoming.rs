@@ -0,0 +1,166 @@
+use super::{BincodeTransport, PrivateDir};
+use crate::auth::verify_token;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tarpc::tokio_serde::formats::Bincode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::length_delimited;
+use tracing::warn;
+
+/// Time for a new connection to present the auth token.
+const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Listener which `Incoming` accepts connections from.
+pub trait Listener: Unpin {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Self::Stream, std::io::Error>>;
+}
+
+impl Listener for tokio::net::TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Self::Stream, std::io::Error>> {
+        tokio::net::TcpListener::poll_accept(self, cx).map_ok(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Self::Stream, std::io::Error>> {
+        tokio::net::UnixListener::poll_accept(self, cx).map_ok(|(stream, _)| stream)
+    }
+}
+
+/// Stream of connections which presented the auth token.
+/// Connections with a wrong token are dropped.
+/// If no token is given, all connections are accepted.
+pub struct AuthIncoming<L: Listener> {
+    listener: L,
+    token: Option<Vec<u8>>,
+    pending: FuturesUnordered<BoxFuture<'static, Option<L::Stream>>>,
+    /// removed when this is dropped
+    dir: Option<PrivateDir>,
+}
+
+impl<L: Listener> AuthIncoming<L> {
+    pub(crate) fn new(listener: L, token: Option<String>) -> Self {
+        Self {
+            listener,
+            token: token.map(String::into_bytes),
+            pending: FuturesUnordered::new(),
+            dir: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &L {
+        &self.listener
+    }
+}
+
+impl<L: Listener> Stream for AuthIncoming<L> {
+    type Item = Result<L::Stream, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok(stream)) => {
+                    let Some(token) = this.token.clone() else {
+                        return Poll::Ready(Some(Ok(stream)));
+                    };
+                    this.pending.push(Box::pin(async move {
+                        let mut stream = stream;
+                        match tokio::time::timeout(AUTH_TIMEOUT, verify_token(&mut stream, &token))
+                            .await
+                        {
+                            Ok(Ok(true)) => Some(stream),
+                            Ok(Ok(false)) => {
+                                warn!("reject connection: invalid auth token");
+                                None
+                            }
+                            Ok(Err(e)) => {
+                                warn!("reject connection: {}", e);
+                                None
+                            }
+                            Err(_) => {
+                                warn!("reject connection: auth token timed out");
+                                None
+                            }
+                        }
+                    }));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match this.pending.poll_next_unpin(cx) {
+                Poll::Ready(Some(Some(stream))) => return Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Some(None)) => continue,
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Stream of tarpc transports, like `tarpc::serde_transport::tcp::Incoming`.
+/// Each connection must present the auth token in its first frame.
+pub struct Incoming<L: Listener, Item, SinkItem> {
+    incoming: AuthIncoming<L>,
+    config: length_delimited::Builder,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<L: Listener, Item, SinkItem> Incoming<L, Item, SinkItem> {
+    pub(crate) fn new(listener: L, token: Option<String>) -> Self {
+        Self {
+            incoming: AuthIncoming::new(listener, token),
+            config: length_delimited::Builder::new(),
+            ghost: PhantomData,
+        }
+    }
+
+    /// Keep `dir` (of the unix domain socket) until this is dropped.
+    pub(crate) fn private_dir(mut self, dir: PrivateDir) -> Self {
+        self.incoming.dir = Some(dir);
+        self
+    }
+
+    pub fn get_ref(&self) -> &L {
+        self.incoming.get_ref()
+    }
+
+    /// Returns an immutable reference to the length-delimited codec's config.
+    pub fn config(&self) -> &length_delimited::Builder {
+        &self.config
+    }
+
+    /// Returns a mutable reference to the length-delimited codec's config.
+    pub fn config_mut(&mut self) -> &mut length_delimited::Builder {
+        &mut self.config
+    }
+}
+
+impl<L, Item, SinkItem> Stream for Incoming<L, Item, SinkItem>
+where
+    L: Listener,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    type Item = Result<BincodeTransport<L::Stream, Item, SinkItem>, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.incoming.poll_next_unpin(cx).map_ok(|stream| {
+            tarpc::serde_transport::new(this.config.new_framed(stream), Bincode::default())
+        })
+    }
+}
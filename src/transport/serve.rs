@@ -0,0 +1,366 @@
+use super::{listen, listen_stdio_with, BincodeTransport, Incoming, Listener};
+use crate::{AppProtocolVersions, NetworkType};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::pin::pin;
+use std::sync::Arc;
+use tarpc::server::{BaseChannel, Channel, Serve};
+use tarpc::{ClientMessage, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet, LocalSet};
+use tokio_util::codec::length_delimited;
+use tracing::{debug, warn};
+
+/// Options of `serve_with`.
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    pub(crate) network_type: NetworkType,
+    pub(crate) multiple_connections: bool,
+    pub(crate) max_concurrent_requests: Option<usize>,
+    pub(crate) max_frame_length: Option<usize>,
+    pub(crate) idle_timeout: Option<std::time::Duration>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            network_type: NetworkType::Tcp,
+            multiple_connections: false,
+            max_concurrent_requests: None,
+            max_frame_length: None,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl ServeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How to talk with the client (default: `NetworkType::Tcp`).
+    /// `NetworkType::Stdio` needs `SshRpcExt::exec_rpc_server_stdio` on the client.
+    pub fn network_type(mut self, network_type: NetworkType) -> Self {
+        self.network_type = network_type;
+        self
+    }
+
+    /// Accept connections until the listener fails (default: false).
+    /// When false, the server exits after the first connection is closed.
+    /// This has no effect in stdio mode.
+    pub fn multiple_connections(mut self, multiple_connections: bool) -> Self {
+        self.multiple_connections = multiple_connections;
+        self
+    }
+
+    /// Number of requests processed at the same time on each connection (default: unlimited).
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.max_concurrent_requests = Some(max);
+        self
+    }
+
+    /// Max length of a frame (default: 8 MiB, see `LengthDelimitedCodec`).
+    pub fn max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = Some(max);
+        self
+    }
+
+    /// Exit when there is no connection for `timeout` (default: wait forever).
+    pub fn idle_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    fn codec(&self) -> length_delimited::Builder {
+        let mut codec = length_delimited::Builder::new();
+        if let Some(max) = self.max_frame_length {
+            codec.max_frame_length(max);
+        }
+        codec
+    }
+}
+
+type ServeTransport<St, S> =
+    BincodeTransport<St, ClientMessage<<S as Serve>::Req>, Response<<S as Serve>::Resp>>;
+
+/// Serve requests on one connection until it is closed.
+///
+/// Each request runs in its own task, so a panic in a handler fails only that request.
+/// The tasks are local to this connection, because the futures of a generic `Serve`
+/// cannot be proven `Send`.
+async fn serve_channel<St, S>(transport: ServeTransport<St, S>, serve: S, options: &ServeOptions)
+where
+    St: AsyncRead + AsyncWrite + 'static,
+    S: Serve + Clone + 'static,
+    S::Req: for<'de> Deserialize<'de>,
+    S::Resp: Serialize,
+{
+    let permits = Arc::new(Semaphore::new(
+        options
+            .max_concurrent_requests
+            .unwrap_or(Semaphore::MAX_PERMITS),
+    ));
+    let mut tasks = JoinSet::new();
+    let local = LocalSet::new();
+    local
+        .run_until(async {
+            let mut responses = pin!(BaseChannel::with_defaults(transport).execute(serve));
+            while let Some(response) = responses.next().await {
+                let permit = permits.clone().acquire_owned().await.expect("never closed");
+                tasks.spawn_local(async move {
+                    response.await;
+                    drop(permit);
+                });
+                while let Some(result) = tasks.try_join_next() {
+                    log_panic(result);
+                }
+            }
+            while let Some(result) = tasks.join_next().await {
+                log_panic(result);
+            }
+        })
+        .await;
+    debug!("connection closed");
+}
+
+fn log_panic(result: Result<(), JoinError>) {
+    if let Err(e) = result {
+        warn!("request failed: {}", e);
+    }
+}
+
+pub(crate) async fn serve_incoming<L, S>(
+    mut incoming: Incoming<L, ClientMessage<S::Req>, Response<S::Resp>>,
+    serve: S,
+    options: &ServeOptions,
+) where
+    L: Listener,
+    S: Serve + Clone + 'static,
+    S::Req: for<'de> Deserialize<'de>,
+    S::Resp: Serialize,
+{
+    *incoming.config_mut() = options.codec();
+
+    let mut channels = FuturesUnordered::new();
+    let mut accepting = true;
+    loop {
+        if !accepting && channels.is_empty() {
+            return;
+        }
+        let idle = channels.is_empty();
+        let idle_timeout = async {
+            match options.idle_timeout {
+                Some(timeout) if idle => tokio::time::sleep(timeout).await,
+                _ => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            transport = incoming.next(), if accepting => match transport {
+                Some(Ok(transport)) => {
+                    debug!("connection accepted");
+                    accepting = options.multiple_connections;
+                    channels.push(serve_channel(transport, serve.clone(), options));
+                }
+                Some(Err(e)) => warn!("failed to accept: {}", e),
+                None => accepting = false,
+            },
+            Some(()) = channels.next(), if !channels.is_empty() => (),
+            _ = idle_timeout => {
+                debug!("exit on idle");
+                return;
+            }
+        }
+    }
+}
+
+/// Serve `serve` (like `HelloServer.serve()` of a `tarpc::service`) with default options.
+/// This prints the handshake information and returns when the client disconnects.
+pub async fn serve<S>(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+    serve: S,
+) -> Result<(), std::io::Error>
+where
+    S: Serve + Clone + 'static,
+    S::Req: for<'de> Deserialize<'de>,
+    S::Resp: Serialize,
+{
+    serve_with(app_protocol_version, serve, &ServeOptions::new()).await
+}
+
+/// Same as `serve`, but configured by `options`.
+pub async fn serve_with<S>(
+    app_protocol_version: impl Into<AppProtocolVersions>,
+    serve: S,
+    options: &ServeOptions,
+) -> Result<(), std::io::Error>
+where
+    S: Serve + Clone + 'static,
+    S::Req: for<'de> Deserialize<'de>,
+    S::Resp: Serialize,
+{
+    match options.network_type {
+        NetworkType::Tcp => {
+            let incoming = listen(app_protocol_version).await?;
+            serve_incoming(incoming, serve, options).await;
+        }
+        #[cfg(unix)]
+        NetworkType::Unix => {
+            let incoming = super::listen_unix(app_protocol_version).await?;
+            serve_incoming(incoming, serve, options).await;
+        }
+        #[cfg(not(unix))]
+        NetworkType::Unix => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix domain socket is not supported",
+            ));
+        }
+        NetworkType::Stdio => {
+            let transport = listen_stdio_with(app_protocol_version, &options.codec())?;
+            serve_channel(transport, serve, options).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::token_frame;
+    use crate::client::authed_transport;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tarpc::{context, ServerError};
+
+    type LocalIncoming = Incoming<tokio::net::TcpListener, ClientMessage<u32>, Response<u32>>;
+
+    /// Enough for the server to notice the end of the connection.
+    const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn add_one() -> impl Serve<Req = u32, Resp = u32> + Clone {
+        tarpc::server::serve(|_, x: u32| async move { Ok::<_, ServerError>(x + 1) })
+    }
+
+    async fn listen_local(token: Option<&str>) -> (LocalIncoming, SocketAddr) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Incoming::new(listener, token.map(str::to_string)), addr)
+    }
+
+    fn client<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        token: Option<&str>,
+    ) -> tarpc::client::Channel<u32, u32> {
+        let transport = authed_transport(stream, token.map(token_frame));
+        tarpc::client::new(Default::default(), transport).spawn()
+    }
+
+    /// Connect, call once and disconnect.
+    async fn call(addr: SocketAddr, token: Option<&str>) -> Result<u32, tarpc::client::RpcError> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client(stream, token).call(context::current(), 1).await
+    }
+
+    #[tokio::test]
+    async fn test_serve_single_connection() {
+        let (incoming, addr) = listen_local(Some("secret")).await;
+        let options = ServeOptions::new();
+        let (served, ()) = tokio::join!(
+            tokio::time::timeout(EXIT_TIMEOUT, serve_incoming(incoming, add_one(), &options)),
+            async {
+                assert!(call(addr, Some("wrong")).await.is_err());
+                assert_eq!(call(addr, Some("secret")).await.unwrap(), 2);
+            },
+        );
+        served.expect("serve returns after the connection is closed");
+    }
+
+    #[tokio::test]
+    async fn test_serve_multiple_connections_idle_timeout() {
+        let (incoming, addr) = listen_local(None).await;
+        let options = ServeOptions::new()
+            .multiple_connections(true)
+            .idle_timeout(Duration::from_millis(200));
+        let (served, ()) = tokio::join!(
+            tokio::time::timeout(EXIT_TIMEOUT, serve_incoming(incoming, add_one(), &options)),
+            async {
+                assert_eq!(call(addr, None).await.unwrap(), 2);
+                assert_eq!(call(addr, None).await.unwrap(), 2);
+            },
+        );
+        served.expect("serve returns after the idle timeout");
+    }
+
+    #[tokio::test]
+    async fn test_serve_idle_timeout_without_connection() {
+        let (incoming, _) = listen_local(None).await;
+        let options = ServeOptions::new().idle_timeout(Duration::from_millis(100));
+        tokio::time::timeout(EXIT_TIMEOUT, serve_incoming(incoming, add_one(), &options))
+            .await
+            .expect("serve returns after the idle timeout");
+    }
+
+    #[tokio::test]
+    async fn test_serve_max_concurrent_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let serve = {
+            let (running, max_running) = (running.clone(), max_running.clone());
+            tarpc::server::serve(move |_, x: u32| {
+                let (running, max_running) = (running.clone(), max_running.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    if x == 0 {
+                        panic!("handler panics");
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, ServerError>(x + 1)
+                }
+            })
+        };
+        let (incoming, addr) = listen_local(None).await;
+        let options = ServeOptions::new().max_concurrent_requests(2);
+        let (served, ()) = tokio::join!(
+            tokio::time::timeout(EXIT_TIMEOUT, serve_incoming(incoming, serve, &options)),
+            async {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let client = client(stream, None);
+                // the panic fails only its own request, which gets no response
+                let mut ctx = context::current();
+                ctx.deadline = std::time::Instant::now() + Duration::from_millis(200);
+                assert!(client.call(ctx, 0).await.is_err());
+                running.store(0, Ordering::SeqCst);
+                let calls = (1..=5).map(|x| client.call(context::current(), x));
+                let results = futures::future::join_all(calls).await;
+                assert!(results.into_iter().all(|r| r.is_ok()));
+            },
+        );
+        served.expect("serve returns after the connection is closed");
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_serve_channel_stdio() {
+        // stdin and stdout of the server are joined into a stream like this
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let options = ServeOptions::new().network_type(NetworkType::Stdio);
+        let transport = tarpc::serde_transport::new(
+            options.codec().new_framed(server_io),
+            tarpc::tokio_serde::formats::Bincode::default(),
+        );
+        let (served, ()) = tokio::join!(
+            tokio::time::timeout(EXIT_TIMEOUT, serve_channel(transport, add_one(), &options)),
+            async {
+                let client = client(client_io, None);
+                assert_eq!(client.call(context::current(), 41).await.unwrap(), 42);
+            },
+        );
+        served.expect("serve returns after stdin is closed");
+    }
+}